@@ -86,7 +86,7 @@ impl<'a> Echo<'a> {
         pkt[2..4].copy_from_slice(&cksum.to_be_bytes());
         pkt[4..6].copy_from_slice(&self.ident.to_be_bytes());
         pkt[6..8].copy_from_slice(&self.seq.to_be_bytes());
-        pkt[8..n].copy_from_slice(&self.body);
+        pkt[8..n].copy_from_slice(self.body);
 
         &pkt[..n]
     }
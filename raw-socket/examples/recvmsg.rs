@@ -27,7 +27,7 @@ async fn main() -> Result<()> {
     loop {
         let iovec = &[IoSliceMut::new(&mut data)];
         let (n, from) = sock.recv_msg(iovec, Some(&mut ctrl)).await?;
-        let cmsgs = CMsg::decode(&mut ctrl).collect::<Vec<_>>();
+        let cmsgs = CMsg::decode(&ctrl).collect::<Vec<_>>();
         println!("{:?}: {:?}: {:?}", from, &data[..n], cmsgs);
     }
 }
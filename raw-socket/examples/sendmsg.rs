@@ -25,7 +25,7 @@ fn main() -> Result<()> {
     let data = IoSlice::new(&data[..]);
 
     let dst = SocketAddr::new("2606:4700:4700::1111".parse()?, 1234);
-    sock.send_msg(dst, &[data], &ctrl)?;
+    sock.send_msg(dst, &[data], ctrl)?;
 
     Ok(())
 }
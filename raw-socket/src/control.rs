@@ -69,6 +69,7 @@ impl<'a> CMsg<'a> {
                 let kind = (*header).cmsg_type;
 
                 let ptr = CMSG_DATA(header);
+                #[allow(clippy::unnecessary_cast)]
                 let len = len as usize;
 
                 next = next_header(&root, header);
@@ -161,6 +162,7 @@ impl Ipv6PktInfo {
         Ipv6Addr::from(self.0.ipi6_addr.s6_addr)
     }
 
+    #[allow(clippy::unnecessary_cast)]
     pub fn ifindex(&self) -> u32 {
         self.0.ipi6_ifindex as u32
     }
@@ -62,6 +62,19 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn bind_device() -> Result<()> {
+        let sock = RawSocket::new(Domain::ipv4(), Type::dgram(), None)?;
+
+        sock.bind_device("lo")?;
+
+        let err = sock.bind_device("nonexistent0").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+
+        Ok(())
+    }
+
     #[test]
     fn send_recv_msg() -> Result<()> {
         let addr = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 0);
@@ -69,12 +82,12 @@ mod test {
         let send = RawSocket::new(Domain::ipv4(), Type::dgram(), None)?;
         let recv = RawSocket::new(Domain::ipv4(), Type::dgram(), None)?;
 
-        send.bind(&addr)?;
-        recv.bind(&addr)?;
+        send.bind(addr)?;
+        recv.bind(addr)?;
 
         let sent = [0u8; 64];
         let addr = recv.local_addr()?;
-        let n = send.send_msg(&addr, &[IoSlice::new(&sent)], &[])?;
+        let n = send.send_msg(addr, &[IoSlice::new(&sent)], &[])?;
 
         assert_eq!(n, sent.len());
 
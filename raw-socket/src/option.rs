@@ -37,12 +37,18 @@ impl Name {
     pub const SO_KEEPALIVE: Name = Name(libc::SO_KEEPALIVE);
     pub const SO_SNDBUF: Name = Name(libc::SO_SNDBUF);
     pub const SO_RCVBUF: Name = Name(libc::SO_RCVBUF);
+    #[cfg(target_os = "linux")]
+    pub const SO_BINDTODEVICE: Name = Name(ffi::SO_BINDTODEVICE);
 
     pub const fn from(n: c_int) -> Self {
         Self(n)
     }
 }
 
+/// # Safety
+///
+/// Implementors must be plain values that can be passed to `getsockopt`
+/// and `setsockopt` by pointer and `size_of`.
 pub unsafe trait Opt: Clone + Default {}
 
 unsafe impl Opt for c_int {}
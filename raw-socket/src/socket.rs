@@ -113,8 +113,8 @@ impl RawSocket {
         let len = &mut len as *mut _;
 
         unsafe {
-            let level = transmute(level);
-            let name = transmute(name);
+            let level = transmute::<Level, c_int>(level);
+            let name = transmute::<Name, c_int>(name);
             match libc::getsockopt(fd, level, name, ptr, len) {
                 0 => Ok(val),
                 _ => Err(Error::last_os_error()),
@@ -128,8 +128,27 @@ impl RawSocket {
         let len = size_of::<O>() as socklen_t;
 
         unsafe {
-            let level = transmute(level);
-            let name = transmute(name);
+            let level = transmute::<Level, c_int>(level);
+            let name = transmute::<Name, c_int>(name);
+            match libc::setsockopt(fd, level, name, ptr, len) {
+                0 => Ok(()),
+                _ => Err(Error::last_os_error()),
+            }
+        }
+    }
+
+    /// Binds the socket to the named interface, so that it only sends and
+    /// receives over it, whatever the routing table says. The name doesn't
+    /// fit `set_sockopt`, which only passes fixed-size values.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(&self, interface: &str) -> Result<()> {
+        let fd = self.as_raw_fd();
+        let ptr = interface.as_ptr() as *const _;
+        let len = interface.len() as socklen_t;
+
+        unsafe {
+            let level = transmute::<Level, c_int>(Level::SOCKET);
+            let name = transmute::<Name, c_int>(Name::SO_BINDTODEVICE);
             match libc::setsockopt(fd, level, name, ptr, len) {
                 0 => Ok(()),
                 _ => Err(Error::last_os_error()),
@@ -162,6 +181,6 @@ fn socketaddr(addr: &SockAddr) -> Result<SocketAddr> {
     match addr.family() as c_int {
         AF_INET => Ok(addr.as_inet().expect("AF_INET addr").into()),
         AF_INET6 => Ok(addr.as_inet6().expect("AF_INET6 addr").into()),
-        _ => Err(Error::new(ErrorKind::Other, "unknown address type")),
+        _ => Err(Error::other("unknown address type")),
     }
 }
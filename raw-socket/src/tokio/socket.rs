@@ -65,6 +65,11 @@ impl RawSocket {
         self.io.get_ref().set_sockopt(level, name, value)
     }
 
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(&self, interface: &str) -> Result<()> {
+        self.io.get_ref().bind_device(interface)
+    }
+
     async fn read<F: FnMut(&crate::RawSocket) -> Result<R>, R>(&self, mut f: F) -> Result<R> {
         loop {
             let mut guard = self.io.readable().await?;
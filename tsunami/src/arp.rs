@@ -0,0 +1,195 @@
+use crate::{
+    net::{build_arp_request, ARP_LEN, ETHERNET_HDR_LEN},
+    pacing::Pacer,
+};
+use anyhow::{bail, Result};
+use pnet::{
+    datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, NetworkInterface},
+    packet::{
+        arp::{ArpOperations, ArpPacket},
+        ethernet::{EtherTypes, EthernetPacket},
+        Packet,
+    },
+    util::MacAddr,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, instrument};
+
+/// Finds the interface `host` is directly attached to among `interfaces`,
+/// if any, along with our address on it. Loopback and interfaces without a
+/// MAC address (tunnels and the like) don't speak ARP, so they're left out,
+/// and so is every interface but `only`, if given.
+pub fn on_link_interface<'a>(
+    interfaces: &'a [NetworkInterface],
+    host: Ipv4Addr,
+    only: Option<&str>,
+) -> Option<(&'a NetworkInterface, Ipv4Addr)> {
+    interfaces
+        .iter()
+        .filter(|interface| only.is_none_or(|only| interface.name == only))
+        .filter(|interface| interface.is_up() && !interface.is_loopback())
+        .filter(|interface| interface.mac.is_some_and(|mac| mac != MacAddr::zero()))
+        .find_map(|interface| {
+            let src_ip_addr = interface.ips.iter().find_map(|ip_net| match ip_net.ip() {
+                IpAddr::V4(ip) if ip_net.contains(IpAddr::V4(host)) => Some(ip),
+                _ => None,
+            })?;
+
+            Some((interface, src_ip_addr))
+        })
+}
+
+/// An Ethernet channel on an interface, along with our MAC address on it.
+pub struct EthernetChannel {
+    src_mac: MacAddr,
+    tx: Box<dyn DataLinkSender>,
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+/// Opens an Ethernet channel on the interface to send the ARP requests and
+/// receive the replies on.
+pub fn open(interface: &NetworkInterface) -> Result<EthernetChannel> {
+    let Some(src_mac) = interface.mac else {
+        bail!("{} has no MAC address", interface.name);
+    };
+
+    /* wake up every now and then, so that the receiver notices the sender is done */
+    let config = Config {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    match datalink::channel(interface, config) {
+        Ok(Channel::Ethernet(tx, rx)) => Ok(EthernetChannel { src_mac, tx, rx }),
+        Ok(_) => bail!("{} isn't an Ethernet interface", interface.name),
+        Err(e) => bail!("can't open a datalink channel on {}: {e}", interface.name),
+    }
+}
+
+/// Finds out which of the hosts on the channel's segment are up by asking
+/// for their MAC addresses over ARP, which gets answered even when ICMP is
+/// blocked. Hosts that stay silent for `max_retries` rounds are considered
+/// down. The datalink channel blocks, so this is meant for spawn_blocking.
+#[instrument(skip_all, name = "arp", fields(interface = %interface.name))]
+pub fn discover(
+    interface: &NetworkInterface,
+    channel: &mut EthernetChannel,
+    src_ip_addr: Ipv4Addr,
+    hosts: &[Ipv4Addr],
+    max_retries: usize,
+    pacer: &mut Pacer,
+) -> Result<HashMap<Ipv4Addr, MacAddr>> {
+    let EthernetChannel { src_mac, tx, rx } = channel;
+    let src_mac = *src_mac;
+
+    let mut up = HashMap::new();
+
+    /* we don't hear our own requests, so our own address is answered for us */
+    if hosts.contains(&src_ip_addr) {
+        up.insert(src_ip_addr, src_mac);
+    }
+
+    for round in 1..=max_retries {
+        let pending = hosts
+            .iter()
+            .filter(|host| !up.contains_key(*host))
+            .copied()
+            .collect::<HashSet<Ipv4Addr>>();
+
+        if pending.is_empty() {
+            break;
+        }
+
+        info!("asking for {} hosts, round {}", pending.len(), round);
+
+        let sent = AtomicBool::new(false);
+
+        /* Keep receiving while the requests go out, so that the replies to
+         * the first ones don't pile up in the socket buffer. Once they're all
+         * out, the receiver waits until it hasn't heard a reply for 300ms,
+         * other traffic on the segment notwithstanding. */
+        let replies = thread::scope(|scope| {
+            let receiver = scope.spawn(|| {
+                let mut replies = HashMap::new();
+                let mut idle_since = Instant::now();
+
+                loop {
+                    match rx.next() {
+                        Ok(frame) => {
+                            if let Some((host, mac)) = arp_reply(frame, src_ip_addr) {
+                                if pending.contains(&host) {
+                                    debug!(%host, %mac, "host is up");
+                                    replies.insert(host, mac);
+                                    idle_since = Instant::now();
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                        Err(e) => return Err(e),
+                    }
+
+                    /* the clock only starts once the requests are all out */
+                    match sent.load(Ordering::Acquire) {
+                        false => idle_since = Instant::now(),
+                        true if idle_since.elapsed() > Duration::from_millis(300) => {
+                            info!("timed out after 300ms");
+                            return Ok(replies);
+                        }
+                        true => {}
+                    }
+                }
+            });
+
+            for host in &pending {
+                let mut buf = [0u8; (ETHERNET_HDR_LEN + ARP_LEN) as usize];
+                let frame = build_arp_request(&mut buf, src_mac, src_ip_addr, *host);
+
+                if let Some(Err(e)) = tx.send_to(frame.packet(), None) {
+                    debug!(%host, "couldn't send the request: {e}");
+                }
+
+                pacer.tick_blocking(buf.len());
+            }
+
+            sent.store(true, Ordering::Release);
+
+            receiver.join().expect("the arp receiver panicked")
+        })?;
+
+        up.extend(replies);
+    }
+
+    info!("exiting");
+
+    Ok(up)
+}
+
+/// Parses an Ethernet frame and returns the sender of the ARP reply it
+/// carries, if it's a reply addressed to us.
+fn arp_reply(frame: &[u8], src_ip_addr: Ipv4Addr) -> Option<(Ipv4Addr, MacAddr)> {
+    let frame = EthernetPacket::new(frame)?;
+
+    if frame.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+
+    let arp_packet = ArpPacket::new(frame.payload())?;
+
+    if arp_packet.get_operation() != ArpOperations::Reply
+        || arp_packet.get_target_proto_addr() != src_ip_addr
+    {
+        return None;
+    }
+
+    Some((
+        arp_packet.get_sender_proto_addr(),
+        arp_packet.get_sender_hw_addr(),
+    ))
+}
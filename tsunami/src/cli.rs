@@ -1,10 +1,14 @@
-use crate::Port;
+use crate::{net::SourcePorts, output::OutputFormat, scan::ScanType, Port};
 use anyhow::{bail, Result};
+use pnet::ipnetwork::IpNetwork;
+use std::net::{IpAddr, Ipv4Addr};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 pub struct Opt {
-    pub target: String,
+    /// Hostnames, addresses, CIDR blocks (10.0.0.0/22) or ranges (10.0.0.1-50)
+    #[structopt(required = true)]
+    pub targets: Vec<Target>,
 
     #[structopt(short, long)]
     pub ports: Option<Vec<Port>>,
@@ -12,20 +16,84 @@ pub struct Opt {
     #[structopt(short, long)]
     pub ranges: Option<Vec<PortRange>>,
 
+    /// How many connect() attempts may be in flight at once (connect scans only)
     #[structopt(short, long, default_value = "512")]
     pub flying_tasks: u16,
 
     #[structopt(short, long, default_value = "3")]
     pub max_retries: usize,
 
+    /// How long to wait for a reply before sending the probe again at the least, in milliseconds
+    #[structopt(long, default_value = "100")]
+    pub min_rtt: u64,
+
+    /// How long to wait for a reply before sending the probe again at the most, in milliseconds
+    #[structopt(long, default_value = "10000")]
+    pub max_rtt: u64,
+
+    /// How long to wait for a reply until the round trip time is known, in milliseconds
+    #[structopt(long, default_value = "1000")]
+    pub initial_rtt: u64,
+
+    /// How many probes go out back to back before a nap
     #[structopt(short, long, default_value = "512")]
     pub batch_size: usize,
 
+    /// How long each connect() attempt naps afterwards, in milliseconds (connect scans only)
     #[structopt(short = "n", long, default_value = "10")]
     pub nap_after_spawn: f64,
 
+    /// How long to nap after each batch of probes, in milliseconds
     #[structopt(short = "N", long, default_value = "10")]
     pub nap_after_batch: f64,
+
+    /// Send this many packets per second, rather than going by the batches and naps
+    #[structopt(long)]
+    pub rate: Option<f64>,
+
+    /// How many packets may go out back to back at --rate [default: 10ms worth]
+    #[structopt(long)]
+    pub burst: Option<u32>,
+
+    /// Keep the probes under this many bits per second (e.g. 500k, 10M, 1G)
+    #[structopt(long)]
+    pub max_bandwidth: Option<Bandwidth>,
+
+    /// Speed up or back off with the loss observed, never going over --rate [default: 10000]
+    #[structopt(long)]
+    pub adaptive: bool,
+
+    /// One of syn, fin, null, xmas, ack, window, udp, connect, sctp, ip
+    #[structopt(short, long, default_value = "syn")]
+    pub scan_type: ScanType,
+
+    /// How long a connect scan waits for the handshake, in milliseconds
+    #[structopt(long, default_value = "1000")]
+    pub connect_timeout: u64,
+
+    /// Send the probes through this interface, whatever the routing table says
+    #[structopt(long)]
+    pub interface: Option<String>,
+
+    /// Send the probes from this address, which the replies have to find their way back to
+    #[structopt(long)]
+    pub source_ip: Option<IpAddr>,
+
+    /// Send the probes from this port, a range of ports (40000-40999) or random ones [default: 4919]
+    #[structopt(long)]
+    pub source_port: Option<SourcePorts>,
+
+    /// Walk the targets in the order this seed gives, to reproduce a scan [default: random]
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Scan every target without pinging it first to see whether it's up
+    #[structopt(long)]
+    pub skip_discovery: bool,
+
+    /// One of text, json, ndjson, xml, grepable, csv
+    #[structopt(long, default_value = "text")]
+    pub output_format: OutputFormat,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -60,3 +128,146 @@ impl std::str::FromStr for PortRange {
         Ok(parsed.into())
     }
 }
+
+/// A bandwidth in bits per second, with an optional k, M or G
+/// (decimal) suffix, the way link speeds are quoted.
+#[derive(Debug, Clone, Copy)]
+pub struct Bandwidth(pub f64);
+
+impl std::str::FromStr for Bandwidth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 1e3),
+            Some((i, 'm' | 'M')) => (&s[..i], 1e6),
+            Some((i, 'g' | 'G')) => (&s[..i], 1e9),
+            _ => (s, 1.0),
+        };
+
+        let bits_per_sec = number.parse::<f64>()? * multiplier;
+        if !bits_per_sec.is_finite() || bits_per_sec <= 0.0 {
+            bail!("expected a positive bandwidth, like 500k, 10M or 1G");
+        }
+
+        Ok(Self(bits_per_sec))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    Host(String),
+    Network(IpNetwork),
+    Range(IpAddr, IpAddr),
+}
+
+impl std::str::FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains('/') {
+            return Ok(Self::Network(s.parse::<IpNetwork>()?));
+        }
+
+        if let Some((start, end)) = s.split_once('-') {
+            if let Ok(start) = start.parse::<IpAddr>() {
+                /* either a full address (10.0.0.1-10.0.0.50)
+                 * or, for IPv4, just the last octet (10.0.0.1-50) */
+                let end = match (start, end.parse::<IpAddr>()) {
+                    (_, Ok(end)) => end,
+                    (IpAddr::V4(start), Err(_)) => {
+                        let [a, b, c, _] = start.octets();
+                        IpAddr::V4(Ipv4Addr::new(a, b, c, end.parse::<u8>()?))
+                    }
+                    (IpAddr::V6(_), Err(e)) => return Err(e.into()),
+                };
+
+                if start.is_ipv4() != end.is_ipv4() {
+                    bail!("range {start}-{end} mixes address families");
+                }
+
+                if start > end {
+                    bail!("range start {start} is past range end {end}");
+                }
+
+                return Ok(Self::Range(start, end));
+            }
+        }
+
+        Ok(Self::Host(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Target;
+    use std::net::IpAddr;
+
+    fn range(target: &str) -> (IpAddr, IpAddr) {
+        match target.parse::<Target>().unwrap() {
+            Target::Range(start, end) => (start, end),
+            target => panic!("{target:?} isn't a range"),
+        }
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn last_octet_range() {
+        assert_eq!(range("10.0.0.1-50"), (addr("10.0.0.1"), addr("10.0.0.50")));
+        assert_eq!(range("10.0.0.7-7"), (addr("10.0.0.7"), addr("10.0.0.7")));
+        assert!("10.0.0.1-256".parse::<Target>().is_err());
+        assert!("10.0.0.50-1".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn full_range() {
+        assert_eq!(
+            range("10.0.0.250-10.0.1.5"),
+            (addr("10.0.0.250"), addr("10.0.1.5"))
+        );
+        assert_eq!(
+            range("fe80::1-fe80::ff"),
+            (addr("fe80::1"), addr("fe80::ff"))
+        );
+        assert!("10.0.1.0-10.0.0.255".parse::<Target>().is_err());
+        /* IPv6 has no short form for the end */
+        assert!("fe80::1-ff".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn mixed_families() {
+        assert!("10.0.0.1-::1".parse::<Target>().is_err());
+        assert!("::1-10.0.0.1".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn hostnames_with_dashes() {
+        for host in [
+            "my-host",
+            "web-01.example.com",
+            "10-0-0-1.example.com",
+            "a-1",
+        ] {
+            match host.parse::<Target>().unwrap() {
+                Target::Host(name) => assert_eq!(name, host),
+                target => panic!("{host} parsed as {target:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn networks() {
+        assert!(matches!(
+            "10.0.0.0/22".parse::<Target>().unwrap(),
+            Target::Network(_)
+        ));
+        assert!(matches!(
+            "::/0".parse::<Target>().unwrap(),
+            Target::Network(_)
+        ));
+        assert!("10.0.0.0/33".parse::<Target>().is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// How long the sending rate is judged over, which has to be long enough
+/// for most of the replies to the probes sent in it to make it back.
+const WINDOW: Duration = Duration::from_millis(200);
+
+/// How much the round trip time estimate is allowed to be off by at the least.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// How many replies a window has to be expected to hold before it's judged,
+/// so that a sparse range of targets isn't mistaken for a lossy link.
+const MIN_EXPECTED_REPLIES: f64 = 200.0;
+
+/// A window that gets back less than this fraction of the replies expected
+/// of it means that the probes (or the replies) are being dropped.
+const LOSS_THRESHOLD: f64 = 0.75;
+
+/// How much a window weighs in the smoothed reply ratio.
+const SMOOTHING: f64 = 0.2;
+
+/// The rate never backs off further than this, in packets per second.
+const MIN_RATE: f64 = 10.0;
+
+/// What the receiver tells the adaptive pacer: how many probes went out
+/// and how many replies came back to them. Only the first transmissions
+/// count, since the ones sent again are mostly the ones nobody answers.
+#[derive(Debug, Default)]
+pub struct Feedback {
+    probes: AtomicU64,
+    replies: AtomicU64,
+}
+
+impl Feedback {
+    pub fn sent(&self) {
+        self.probes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reply(&self) {
+        self.replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn probes(&self) -> u64 {
+        self.probes.load(Ordering::Relaxed)
+    }
+
+    fn replies(&self) -> u64 {
+        self.replies.load(Ordering::Relaxed)
+    }
+}
+
+/// Adapts the sending rate to the loss observed, the way TCP (and nmap's
+/// timing engine) does: it starts slow and doubles every window until
+/// something is lost, then halves on loss and creeps back up otherwise.
+/// Loss is told by the ratio of replies to probes sent, compared with the
+/// best (smoothed) ratio so far, since the targets only answer some of the
+/// probes to begin with. The probes are spread evenly across the targets,
+/// so that ratio holds steady throughout a scan unless probes get dropped.
+/// The baseline never follows the ratio down, or a link that's losing more
+/// and more would pass for one that never was.
+#[derive(Debug, Clone)]
+pub struct Aimd {
+    feedback: Arc<Feedback>,
+    rate: f64,
+    max_rate: f64,
+    slow_start: bool,
+    smoothed: Option<f64>,
+    baseline: Option<f64>,
+    window_start: Instant,
+    window_probes: u64,
+    window_replies: u64,
+}
+
+impl Aimd {
+    pub fn new(feedback: Arc<Feedback>, max_rate: f64) -> Self {
+        let probes = feedback.probes();
+        let replies = feedback.replies();
+
+        Self {
+            feedback,
+            rate: (max_rate / 10.0).max(MIN_RATE).min(max_rate),
+            max_rate,
+            slow_start: true,
+            smoothed: None,
+            baseline: None,
+            window_start: Instant::now(),
+            window_probes: probes,
+            window_replies: replies,
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Checks on the replies once a window, and returns the new rate if it
+    /// changed. Windows without enough probes sent for the first time (with
+    /// only the retransmissions going out at the end of a scan, say) stay open.
+    pub fn poll(&mut self) -> Option<f64> {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> Option<f64> {
+        let sent = (self.feedback.probes() - self.window_probes) as f64;
+        if now.duration_since(self.window_start) < WINDOW || sent < MIN_EXPECTED_REPLIES {
+            return None;
+        }
+
+        /* the replies to the previous window that straggle into this one
+         * could make it look better than a probe per reply otherwise */
+        let replies = (self.feedback.replies() - self.window_replies) as f64;
+        let ratio = (replies / sent).min(1.0);
+
+        let loss = match self.baseline {
+            /* keep the window open until there's enough to go by */
+            Some(baseline) if baseline * sent < MIN_EXPECTED_REPLIES => return None,
+            Some(baseline) => ratio < baseline * LOSS_THRESHOLD,
+            None => false,
+        };
+
+        if loss {
+            self.rate = (self.rate / 2.0).max(MIN_RATE);
+            self.slow_start = false;
+            debug!(rate = self.rate, ratio, "lost probes, backing off");
+        } else {
+            /* a baseline learned from a handful of replies would be noise */
+            if replies >= MIN_EXPECTED_REPLIES {
+                let smoothed = match self.smoothed {
+                    Some(smoothed) => smoothed + SMOOTHING * (ratio - smoothed),
+                    None => ratio,
+                };
+                self.smoothed = Some(smoothed);
+                self.baseline = Some(self.baseline.map_or(smoothed, |b| b.max(smoothed)));
+            }
+
+            self.rate = match self.slow_start {
+                true => self.rate * 2.0,
+                false => self.rate + self.max_rate / 200.0,
+            }
+            .min(self.max_rate);
+            debug!(rate = self.rate, ratio, "no loss, speeding up");
+        }
+
+        self.start_window(now);
+
+        Some(self.rate)
+    }
+
+    fn start_window(&mut self, now: Instant) {
+        self.window_start = now;
+        self.window_probes = self.feedback.probes();
+        self.window_replies = self.feedback.replies();
+    }
+}
+
+/// Estimates the round trip time from the replies, and from it how long to
+/// wait for one before sending the probe again: the retransmission timeout
+/// of TCP (RFC 6298), kept within bounds.
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    initial: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl RttEstimator {
+    /// `initial` is the timeout until there's a reply to go by,
+    /// and it has to be the case that `min <= max`.
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            initial,
+            min,
+            max,
+        }
+    }
+
+    /// Takes a round trip time into account. It has to be from a probe that
+    /// was only sent once, since there's no telling which of the copies
+    /// a reply is to otherwise (Karn's algorithm).
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+        }
+    }
+
+    /// How long to wait for a reply to a probe that was sent `retried`
+    /// times, backing off exponentially for the retransmissions.
+    pub fn timeout(&self, retried: usize) -> Duration {
+        let rto = match self.srtt {
+            Some(srtt) => srtt + (self.rttvar * 4).max(CLOCK_GRANULARITY),
+            None => self.initial,
+        };
+        let backoff = 1 << retried.saturating_sub(1).min(16);
+
+        rto.clamp(self.min, self.max)
+            .saturating_mul(backoff)
+            .min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Aimd, Feedback, RttEstimator, WINDOW};
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Sends `probes` and gets `replies` back, then polls once the window
+    /// is over.
+    fn window(aimd: &mut Aimd, feedback: &Feedback, probes: u64, replies: u64) -> Option<f64> {
+        feedback.probes.fetch_add(probes, Ordering::Relaxed);
+        feedback.replies.fetch_add(replies, Ordering::Relaxed);
+
+        aimd.poll_at(aimd.window_start + WINDOW)
+    }
+
+    #[test]
+    fn aimd() {
+        let feedback = Arc::new(Feedback::default());
+        let mut aimd = Aimd::new(feedback.clone(), 10_000.0);
+        assert_eq!(aimd.rate(), 1000.0);
+
+        /* slow start doubles the rate for every window without loss */
+        assert_eq!(window(&mut aimd, &feedback, 1000, 500), Some(2000.0));
+        assert_eq!(window(&mut aimd, &feedback, 1000, 500), Some(4000.0));
+
+        /* a ratio under 3/4 of the baseline is loss, which halves it */
+        assert_eq!(window(&mut aimd, &feedback, 1000, 300), Some(2000.0));
+
+        /* from then on, it goes up by 1/200 of the max rate at a time */
+        assert_eq!(window(&mut aimd, &feedback, 1000, 500), Some(2050.0));
+        assert_eq!(window(&mut aimd, &feedback, 1000, 400), Some(2100.0));
+
+        /* and never goes over the max rate */
+        for _ in 0..1000 {
+            window(&mut aimd, &feedback, 1000, 500);
+        }
+        assert_eq!(aimd.rate(), 10_000.0);
+    }
+
+    #[test]
+    fn aimd_waits_for_enough_to_go_by() {
+        let feedback = Arc::new(Feedback::default());
+        let mut aimd = Aimd::new(feedback.clone(), 10_000.0);
+
+        /* not before the window is over */
+        feedback.probes.fetch_add(1000, Ordering::Relaxed);
+        let start = aimd.window_start;
+        assert_eq!(aimd.poll_at(start + WINDOW - MS), None);
+
+        /* nor with too few probes sent in it, retransmissions aside */
+        feedback.probes.fetch_sub(900, Ordering::Relaxed);
+        assert_eq!(aimd.poll_at(start + WINDOW), None);
+
+        /* the window stays open until there are, which sets the baseline
+         * to the 1% of the probes that get a reply */
+        assert_eq!(window(&mut aimd, &feedback, 19_900, 200), Some(2000.0));
+
+        /* at 1%, it takes 20000 probes for a window to say anything */
+        assert_eq!(window(&mut aimd, &feedback, 10_000, 0), None);
+        assert_eq!(window(&mut aimd, &feedback, 10_000, 0), Some(1000.0));
+    }
+
+    #[test]
+    fn aimd_min_rate() {
+        let feedback = Arc::new(Feedback::default());
+        let mut aimd = Aimd::new(feedback.clone(), 20.0);
+        assert_eq!(aimd.rate(), 10.0);
+
+        window(&mut aimd, &feedback, 1000, 1000);
+        assert_eq!(window(&mut aimd, &feedback, 1000, 0), Some(10.0));
+    }
+
+    /* RFC 6298, section 2 */
+    #[test]
+    fn rto() {
+        let mut rtt = RttEstimator::new(1000 * MS, 100 * MS, 10_000 * MS);
+
+        /* the initial timeout until there's a sample */
+        assert_eq!(rtt.timeout(1), 1000 * MS);
+        assert_eq!(rtt.timeout(2), 2000 * MS);
+
+        /* SRTT = R, RTTVAR = R/2, RTO = SRTT + 4 * RTTVAR */
+        rtt.sample(200 * MS);
+        assert_eq!(rtt.timeout(1), 600 * MS);
+
+        /* RTTVAR = 3/4 RTTVAR + 1/4 |SRTT - R'|, SRTT = 7/8 SRTT + 1/8 R' */
+        rtt.sample(400 * MS);
+        assert_eq!(rtt.srtt, Some(225 * MS));
+        assert_eq!(rtt.rttvar, 125 * MS);
+        assert_eq!(rtt.timeout(1), 725 * MS);
+
+        /* backing off doubles it for every retransmission, up to the max */
+        assert_eq!(rtt.timeout(3), 2900 * MS);
+        assert_eq!(rtt.timeout(5), 10_000 * MS);
+        assert_eq!(rtt.timeout(usize::MAX), 10_000 * MS);
+    }
+
+    #[test]
+    fn rto_bounds() {
+        let mut rtt = RttEstimator::new(1000 * MS, 100 * MS, 10_000 * MS);
+
+        /* a fast link still waits the min, a slow one no more than the max */
+        rtt.sample(Duration::from_micros(10));
+        assert_eq!(rtt.timeout(1), 100 * MS);
+
+        let mut rtt = RttEstimator::new(1000 * MS, 100 * MS, 10_000 * MS);
+        rtt.sample(5000 * MS);
+        assert_eq!(rtt.timeout(1), 10_000 * MS);
+
+        /* and the initial timeout is kept within bounds too */
+        let rtt = RttEstimator::new(50 * MS, 100 * MS, 10_000 * MS);
+        assert_eq!(rtt.timeout(1), 100 * MS);
+    }
+}
@@ -0,0 +1,492 @@
+use crate::{
+    arp::{self, on_link_interface},
+    congestion::RttEstimator,
+    net::{
+        build_icmp_packet, build_ipv4_packet, build_ipv6_packet, build_tcp_packet,
+        create_recv_sock, create_send_sock, ipv4_payload, recv_on, send_to, sequence_number,
+        SourcePorts, IPV6_HDR_LEN, IP_HDR_LEN, MAX_PACKET_LEN, SOURCE_PORT, TCP_HDR_LEN,
+    },
+    pacing::Pacer,
+    scan::ScanType,
+    HostReason, Port,
+};
+use anyhow::{bail, Result};
+use pnet::{
+    datalink::{self, NetworkInterface},
+    packet::{
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        tcp::{TcpFlags, TcpPacket},
+        Packet,
+    },
+    util::MacAddr,
+};
+use raw_socket::{
+    tokio::prelude::{Level, Name, RawSocket},
+    Domain, Protocol,
+};
+use std::{
+    collections::{
+        hash_map::{Entry, RandomState},
+        HashMap,
+    },
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info, instrument};
+
+/// Ports the TCP SYN pings go to. A live host answers with SYN-ACK
+/// or RST, depending on whether the port is open.
+const SYN_PING_PORTS: [Port; 2] = [22, 443];
+
+/// Ports the TCP ACK pings go to. These get past stateless firewalls that
+/// only drop incoming SYNs, and a live host answers them with RST.
+const ACK_PING_PORTS: [Port; 1] = [80];
+
+const ICMP_ECHO_LEN: usize = 8;
+const ICMP_TIMESTAMP_LEN: usize = 20;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const TIMESTAMP_REQUEST: u8 = 13;
+const TIMESTAMP_REPLY: u8 = 14;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// A packet that came in on one of the sockets.
+enum Event {
+    Tcp(usize),
+    Icmp(usize),
+    Tcp6(usize, SocketAddr),
+    Icmp6(usize, SocketAddr),
+}
+
+/// Finds out which hosts are up before their ports get scanned. IPv4 hosts
+/// on a directly attached segment are asked for their MAC address over ARP,
+/// everything else gets pinged. Returns the hosts that are up, along with
+/// what told us so and the MAC addresses of the ones that were found over
+/// ARP. Each round of pings waits for the replies as long as a probe would
+/// for its reply.
+#[instrument(skip_all, name = "discovery")]
+#[allow(clippy::too_many_arguments)]
+pub async fn discover(
+    hosts: &[IpAddr],
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+    max_retries: usize,
+    rtt: &RttEstimator,
+    pacer: &Pacer,
+    source_ports: SourcePorts,
+    interface: Option<&str>,
+) -> Result<HashMap<IpAddr, (HostReason, Option<MacAddr>)>> {
+    /* group the on-link hosts by the interface they're attached to */
+    let mut on_link: HashMap<String, (NetworkInterface, Ipv4Addr, Vec<Ipv4Addr>)> = HashMap::new();
+    let mut routed = vec![];
+    let interfaces = datalink::interfaces();
+
+    for host in hosts {
+        match host {
+            IpAddr::V4(ipv4) => match on_link_interface(&interfaces, *ipv4, interface) {
+                Some((interface, src_ip_addr)) => {
+                    on_link
+                        .entry(interface.name.clone())
+                        .or_insert_with(|| (interface.clone(), src_ip_addr, vec![]))
+                        .2
+                        .push(*ipv4);
+                }
+                None => routed.push(*host),
+            },
+            IpAddr::V6(_) => routed.push(*host),
+        }
+    }
+
+    /* the hosts on an interface we can't speak ARP on get pinged instead */
+    let mut channels = vec![];
+    for (interface, src_ip_addr, on_link_hosts) in on_link.into_values() {
+        match arp::open(&interface) {
+            Ok(channel) => channels.push((interface, channel, src_ip_addr, on_link_hosts)),
+            Err(e) => {
+                eprintln!("tsunami: {e}, pinging the hosts on it instead");
+                routed.extend(on_link_hosts.into_iter().map(IpAddr::V4));
+            }
+        }
+    }
+
+    debug!(
+        "{} hosts on-link, {} hosts routed",
+        hosts.len() - routed.len(),
+        routed.len()
+    );
+
+    /* the datalink channels block, so ARP runs on its own thread
+     * while the pings go out */
+    let mut arp_pacer = pacer.clone();
+    let arp = tokio::task::spawn_blocking(move || {
+        let mut up = HashMap::new();
+        for (interface, mut channel, src_ip_addr, hosts) in channels {
+            up.extend(arp::discover(
+                &interface,
+                &mut channel,
+                src_ip_addr,
+                &hosts,
+                max_retries,
+                &mut arp_pacer,
+            )?);
+        }
+        Ok::<_, anyhow::Error>(up)
+    });
+
+    let pinged = ping_sweep(
+        &routed,
+        src_ip_addrs,
+        key,
+        max_retries,
+        rtt,
+        pacer.clone(),
+        source_ports,
+        interface,
+    )
+    .await?;
+
+    let mut up: HashMap<IpAddr, (HostReason, Option<MacAddr>)> = pinged
+        .into_iter()
+        .map(|(host, reason)| (host, (reason, None)))
+        .collect();
+
+    for (host, mac) in arp.await?? {
+        up.insert(IpAddr::V4(host), (HostReason::ArpResponse, Some(mac)));
+    }
+
+    Ok(up)
+}
+
+/// Pings each of the hosts with an ICMP echo request, an ICMP timestamp
+/// request (IPv4 only) and TCP SYN and ACK pings. Any reply to any of them
+/// will do. Hosts that stay silent for `max_retries` rounds are considered
+/// down, with the wait after each round backing off the way a probe's does.
+#[allow(clippy::too_many_arguments)]
+async fn ping_sweep(
+    hosts: &[IpAddr],
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+    max_retries: usize,
+    rtt: &RttEstimator,
+    mut pacer: Pacer,
+    source_ports: SourcePorts,
+    interface: Option<&str>,
+) -> Result<HashMap<IpAddr, HostReason>> {
+    if hosts.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    /* only open the sockets for the address families we're going to see */
+    let (send_sock, sock, icmp_sock) = match hosts.iter().any(IpAddr::is_ipv4) {
+        true => {
+            let send_sock = create_send_sock(Domain::ipv4(), interface)?;
+            send_sock.set_sockopt(Level::IPV4, Name::IPV4_HDRINCL, &1i32)?;
+            (
+                Some(send_sock),
+                Some(create_recv_sock(
+                    Domain::ipv4(),
+                    Protocol::tcp(),
+                    interface,
+                )?),
+                Some(create_recv_sock(
+                    Domain::ipv4(),
+                    Protocol::icmpv4(),
+                    interface,
+                )?),
+            )
+        }
+        false => (None, None, None),
+    };
+    let (send_sock6, sock6, icmp_sock6) = match hosts.iter().any(IpAddr::is_ipv6) {
+        true => (
+            Some(create_send_sock(Domain::ipv6(), interface)?),
+            Some(create_recv_sock(
+                Domain::ipv6(),
+                Protocol::tcp(),
+                interface,
+            )?),
+            Some(create_recv_sock(
+                Domain::ipv6(),
+                Protocol::icmpv6(),
+                interface,
+            )?),
+        ),
+        false => (None, None, None),
+    };
+    let mut buf = vec![0u8; MAX_PACKET_LEN];
+    let mut icmp_buf = vec![0u8; MAX_PACKET_LEN];
+    let mut buf6 = vec![0u8; MAX_PACKET_LEN];
+    let mut icmp_buf6 = vec![0u8; MAX_PACKET_LEN];
+
+    let mut up = HashMap::new();
+
+    for round in 1..=max_retries {
+        let pending = hosts
+            .iter()
+            .filter(|host| !up.contains_key(*host))
+            .copied()
+            .collect::<Vec<IpAddr>>();
+
+        if pending.is_empty() {
+            break;
+        }
+
+        info!("pinging {} hosts, round {}", pending.len(), round);
+
+        /* keep receiving while the pings go out, so that the replies
+         * to the first ones don't pile up in the socket buffers */
+        let pinging = ping(
+            &pending,
+            send_sock.as_ref(),
+            send_sock6.as_ref(),
+            src_ip_addrs,
+            key,
+            source_ports,
+            &mut pacer,
+        );
+        tokio::pin!(pinging);
+
+        /* the round is over once the last ping had the time to be answered */
+        let timeout = rtt.timeout(round);
+        let mut deadline = None;
+
+        loop {
+            let event = tokio::select! {
+                result = &mut pinging, if deadline.is_none() => {
+                    result?;
+                    deadline = Some(Instant::now() + timeout);
+                    continue;
+                }
+                r = recv_on(sock.as_ref(), &mut buf) => r.map(|(n, _)| Event::Tcp(n))?,
+                r = recv_on(icmp_sock.as_ref(), &mut icmp_buf) => r.map(|(n, _)| Event::Icmp(n))?,
+                r = recv_on(sock6.as_ref(), &mut buf6) => r.map(|(n, addr)| Event::Tcp6(n, addr))?,
+                r = recv_on(icmp_sock6.as_ref(), &mut icmp_buf6) => {
+                    r.map(|(n, addr)| Event::Icmp6(n, addr))?
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    info!("timed out after {timeout:?}");
+                    break;
+                }
+            };
+
+            /* Raw IPv4 sockets hand us the IP header as well, whereas raw
+             * IPv6 sockets only hand us what comes after it. */
+            let pong = match event {
+                Event::Tcp(n) => ipv4_payload(&buf[..n], IpNextHeaderProtocols::Tcp).and_then(
+                    |(src, segment)| {
+                        let host = IpAddr::V4(src);
+                        tcp_pong(host, segment, src_ip_addrs, key, source_ports)
+                            .map(|reason| (host, reason))
+                    },
+                ),
+                Event::Icmp(n) => ipv4_payload(&icmp_buf[..n], IpNextHeaderProtocols::Icmp)
+                    .and_then(|(src, message)| {
+                        let host = IpAddr::V4(src);
+                        let replies = [ECHO_REPLY, TIMESTAMP_REPLY];
+                        icmp_pong(host, message, &replies, src_ip_addrs, key)
+                            .map(|reason| (host, reason))
+                    }),
+                Event::Tcp6(n, addr) => {
+                    tcp_pong(addr.ip(), &buf6[..n], src_ip_addrs, key, source_ports)
+                        .map(|reason| (addr.ip(), reason))
+                }
+                Event::Icmp6(n, addr) => {
+                    let replies = [ICMPV6_ECHO_REPLY];
+                    icmp_pong(addr.ip(), &icmp_buf6[..n], &replies, src_ip_addrs, key)
+                        .map(|reason| (addr.ip(), reason))
+                }
+            };
+
+            if let Some((host, reason)) = pong {
+                if let Entry::Vacant(entry) = up.entry(host) {
+                    debug!(%host, %reason, "host is up");
+                    entry.insert(reason);
+                }
+            }
+        }
+    }
+
+    info!("exiting");
+
+    Ok(up)
+}
+
+/// Sends every ping to each of the hosts.
+async fn ping(
+    hosts: &[IpAddr],
+    send_sock: Option<&RawSocket>,
+    send_sock6: Option<&RawSocket>,
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+    source_ports: SourcePorts,
+    pacer: &mut Pacer,
+) -> Result<()> {
+    for host in hosts {
+        let src_ip_addr = src_ip_addrs[host];
+
+        /* ICMP queries have no ports, so they get the cookie of port 0,
+         * truncated to fit the sequence number field */
+        let seq = sequence_number(key, src_ip_addr, SOURCE_PORT, *host, 0) as u16;
+        let icmp_types = match host {
+            IpAddr::V4(_) => vec![
+                (ECHO_REQUEST, ICMP_ECHO_LEN),
+                (TIMESTAMP_REQUEST, ICMP_TIMESTAMP_LEN),
+            ],
+            IpAddr::V6(_) => vec![(ICMPV6_ECHO_REQUEST, ICMP_ECHO_LEN)],
+        };
+        let (icmp_protocol, ip_hdr_len) = match host {
+            IpAddr::V4(_) => (IpNextHeaderProtocols::Icmp, IP_HDR_LEN as usize),
+            IpAddr::V6(_) => (IpNextHeaderProtocols::Icmpv6, IPV6_HDR_LEN as usize),
+        };
+
+        let mut probes = vec![];
+
+        for (icmp_type, len) in icmp_types {
+            let mut icmp_buf = vec![0u8; len];
+            build_icmp_packet(
+                &mut icmp_buf,
+                icmp_type,
+                SOURCE_PORT,
+                seq,
+                src_ip_addr,
+                *host,
+            );
+            probes.push((icmp_protocol, icmp_buf));
+        }
+
+        let tcp_pings = SYN_PING_PORTS
+            .iter()
+            .map(|port| (*port, TcpFlags::SYN))
+            .chain(ACK_PING_PORTS.iter().map(|port| (*port, TcpFlags::ACK)));
+
+        for (port, flags) in tcp_pings {
+            let mut tcp_buf = vec![0u8; TCP_HDR_LEN as usize];
+            let src_port = source_ports.pick(key, src_ip_addr, *host, port);
+            let sequence = sequence_number(key, src_ip_addr, src_port, *host, port);
+            build_tcp_packet(
+                &mut tcp_buf,
+                *host,
+                port,
+                src_ip_addr,
+                src_port,
+                sequence,
+                flags,
+            )?;
+            probes.push((IpNextHeaderProtocols::Tcp, tcp_buf));
+        }
+
+        for (protocol, transport_buf) in probes {
+            send(
+                send_sock,
+                send_sock6,
+                src_ip_addr,
+                *host,
+                protocol,
+                &transport_buf,
+            )
+            .await?;
+            pacer.tick(ip_hdr_len + transport_buf.len()).await;
+        }
+        debug!(%host, "sent the pings");
+    }
+
+    Ok(())
+}
+
+/// Wraps the transport bytes in an IP header and sends them off.
+async fn send(
+    send_sock: Option<&RawSocket>,
+    send_sock6: Option<&RawSocket>,
+    src_ip_addr: IpAddr,
+    dest: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    transport_buf: &[u8],
+) -> Result<()> {
+    match (src_ip_addr, dest, send_sock, send_sock6) {
+        (IpAddr::V4(src), IpAddr::V4(dest), Some(sock), _) => {
+            let mut ipv4_buf = vec![0u8; IP_HDR_LEN as usize + transport_buf.len()];
+            let mut ipv4_packet = build_ipv4_packet(&mut ipv4_buf, src, dest, protocol);
+            ipv4_packet.set_payload(transport_buf);
+
+            send_to(sock, ipv4_packet.packet(), IpAddr::V4(dest), 0).await?;
+        }
+        (IpAddr::V6(src), IpAddr::V6(dest), _, Some(sock)) => {
+            let mut ipv6_buf = vec![0u8; IPV6_HDR_LEN as usize + transport_buf.len()];
+            let mut ipv6_packet = build_ipv6_packet(&mut ipv6_buf, src, dest, protocol);
+            ipv6_packet.set_payload(transport_buf);
+
+            send_to(sock, ipv6_packet.packet(), IpAddr::V6(dest), 0).await?;
+        }
+        _ => bail!("address family mismatch between {src_ip_addr} and {dest}"),
+    }
+
+    Ok(())
+}
+
+/// Checks that a TCP segment answers one of our SYN or ACK pings, and
+/// tells which way it does.
+fn tcp_pong(
+    host: IpAddr,
+    segment: &[u8],
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+    source_ports: SourcePorts,
+) -> Option<HostReason> {
+    let src_ip_addr = src_ip_addrs.get(&host)?;
+    let tcp_packet = TcpPacket::new(segment)?;
+
+    let port = tcp_packet.get_source();
+    let src_port = source_ports.pick(key, *src_ip_addr, host, port);
+    if tcp_packet.get_destination() != src_port {
+        return None;
+    }
+
+    /* a SYN ping is answered with an acknowledgement of the SYN, whereas
+     * an ACK ping is answered with a RST that takes its sequence number
+     * from our acknowledgement number, both derived from the cookie */
+    let sequence = sequence_number(key, *src_ip_addr, src_port, host, port);
+
+    let pong = (SYN_PING_PORTS.contains(&port)
+        && tcp_packet.get_acknowledgement() == ScanType::Syn.expected_ack(sequence))
+        || (ACK_PING_PORTS.contains(&port)
+            && Some(tcp_packet.get_sequence()) == ScanType::Ack.expected_seq(sequence));
+
+    match tcp_packet.get_flags() & TcpFlags::RST {
+        _ if !pong => None,
+        0 => Some(HostReason::SynAck),
+        _ => Some(HostReason::Reset),
+    }
+}
+
+/// Checks that an ICMP message is one of the given reply types and echoes
+/// the identifier and sequence number of our queries, and tells which
+/// of the replies it is.
+fn icmp_pong(
+    host: IpAddr,
+    message: &[u8],
+    replies: &[u8],
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+) -> Option<HostReason> {
+    let src_ip_addr = src_ip_addrs.get(&host)?;
+
+    if message.len() < ICMP_ECHO_LEN || !replies.contains(&message[0]) {
+        return None;
+    }
+
+    let ident = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+
+    if ident != SOURCE_PORT
+        || seq != sequence_number(key, *src_ip_addr, SOURCE_PORT, host, 0) as u16
+    {
+        return None;
+    }
+
+    match message[0] {
+        TIMESTAMP_REPLY => Some(HostReason::TimestampReply),
+        _ => Some(HostReason::EchoReply),
+    }
+}
@@ -1,19 +1,57 @@
+pub mod arp;
 pub mod cli;
+pub mod congestion;
+pub mod discovery;
 pub mod net;
+pub mod oui;
+pub mod output;
+pub mod pacing;
+pub mod payloads;
+pub mod permutation;
 pub mod receiver;
+pub mod route;
+pub mod scan;
+pub mod template;
+pub mod transmitter;
 pub mod worker;
 
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
 pub type Port = u16;
 
+/// A single (host, port) pair to be inspected.
+pub type Probe = (IpAddr, Port);
+
+/// The status a probe was found to be in, along with why.
+pub type Verdict = (Probe, PortStatus, Reason);
+
+/// A probe that went out, along with when.
+pub type Stamp = (Probe, Instant);
+
 pub enum Message {
-    Payload(Vec<Port>),
+    Payload(Vec<Probe>),
     Break,
 }
 
+/// What there is to tell about the reply a verdict was reached on, besides
+/// the port status: the TTL (or hop limit) it came in with, and for TCP,
+/// the window it advertised.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Response {
+    pub ttl: Option<u8>,
+    pub window: Option<u16>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PortInfo {
     status: PortStatus,
     retried: usize,
+    reason: Option<Reason>,
+    rtt: Option<Duration>,
+    response: Response,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -21,9 +59,101 @@ pub enum PortStatus {
     Open,
     Closed,
     Filtered,
+    /// Reachable, but whether it's open or closed can't be told.
+    Unfiltered,
+    /// Either open or filtered, since neither yields a response.
+    OpenFiltered,
     NotInspected,
 }
 
+impl std::fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open => write!(f, "open"),
+            Self::Closed => write!(f, "closed"),
+            Self::Filtered => write!(f, "filtered"),
+            Self::Unfiltered => write!(f, "unfiltered"),
+            Self::OpenFiltered => write!(f, "open|filtered"),
+            Self::NotInspected => write!(f, "not inspected"),
+        }
+    }
+}
+
+/// What made us settle on a port's status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Reason {
+    SynAck,
+    Syn,
+    Reset,
+    UdpResponse,
+    ConnRefused,
+    InitAck,
+    Abort,
+    IcmpUnreachable { code: u8 },
+    Icmpv6Unreachable { code: u8 },
+    NoResponse,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SynAck => write!(f, "syn-ack"),
+            Self::Syn => write!(f, "split-handshake-syn"),
+            Self::Reset => write!(f, "reset"),
+            Self::UdpResponse => write!(f, "udp-response"),
+            Self::ConnRefused => write!(f, "conn-refused"),
+            Self::InitAck => write!(f, "init-ack"),
+            Self::Abort => write!(f, "abort"),
+            Self::IcmpUnreachable { code } => match code {
+                0 => write!(f, "net-unreach"),
+                1 => write!(f, "host-unreach"),
+                2 => write!(f, "proto-unreach"),
+                3 => write!(f, "port-unreach"),
+                9 => write!(f, "net-prohibited"),
+                10 => write!(f, "host-prohibited"),
+                13 => write!(f, "admin-prohibited"),
+                _ => write!(f, "icmp-unreach-{code}"),
+            },
+            Self::Icmpv6Unreachable { code } => match code {
+                0 => write!(f, "no-route"),
+                1 => write!(f, "admin-prohibited"),
+                2 => write!(f, "beyond-scope"),
+                3 => write!(f, "addr-unreach"),
+                4 => write!(f, "port-unreach"),
+                5 => write!(f, "failed-policy"),
+                6 => write!(f, "reject-route"),
+                _ => write!(f, "icmpv6-unreach-{code}"),
+            },
+            Self::NoResponse => write!(f, "no-response"),
+        }
+    }
+}
+
+/// What told us a host is up, named the way nmap names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostReason {
+    ArpResponse,
+    EchoReply,
+    TimestampReply,
+    SynAck,
+    Reset,
+    /// Nothing did, it was taken to be up (--skip-discovery).
+    UserSet,
+}
+
+impl std::fmt::Display for HostReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ArpResponse => write!(f, "arp-response"),
+            Self::EchoReply => write!(f, "echo-reply"),
+            Self::TimestampReply => write!(f, "timestamp-reply"),
+            Self::SynAck => write!(f, "syn-ack"),
+            Self::Reset => write!(f, "reset"),
+            Self::UserSet => write!(f, "user-set"),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! error_and_bail {
     ($msg:expr) => {{
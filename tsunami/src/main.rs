@@ -1,23 +1,37 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::net::IpAddr;
-use std::{collections::HashSet, sync::Arc};
-use structopt::StructOpt;
-use tokio::{
-    sync::{mpsc, Semaphore},
-    time::{sleep, Duration},
+use std::{
+    collections::{hash_map::RandomState, BTreeSet, HashMap},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
+use structopt::StructOpt;
+use tokio::sync::{mpsc, Semaphore};
 use tracing::{debug, info, instrument};
+use tracing_subscriber::EnvFilter;
 use tsunami::{
-    cli::{Opt, PortRange},
-    net::{get_default_gateway_interface, to_ipaddr},
+    cli::{Opt, PortRange, Target},
+    congestion::{Feedback, RttEstimator},
+    discovery::discover,
+    net::{raw_sockets_available, resolve_targets, SourcePorts, IP_HDR_LEN, TCP_HDR_LEN},
+    output::{Hosts, OutputFormat},
+    pacing::Pacer,
+    permutation::Permutation,
     receiver::receive,
-    worker::inspect,
-    {Message, Port},
+    route::RoutingTable,
+    scan::ScanType,
+    transmitter::Transmitter,
+    worker::connect,
+    {HostReason, Message, Port, Probe},
 };
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    /* the logs go to stderr, so that stdout has nothing but the results */
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
 
     let opts = Opt::from_args();
 
@@ -26,15 +40,53 @@ async fn main() {
         return;
     }
 
+    if opts.rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+        eprintln!("--rate has to be a positive number of packets per second");
+        return;
+    }
+
+    if opts.min_rtt > opts.max_rtt {
+        eprintln!("--min-rtt can't be greater than --max-rtt");
+        return;
+    }
+
+    let rtt = RttEstimator::new(
+        Duration::from_millis(opts.initial_rtt),
+        Duration::from_millis(opts.min_rtt),
+        Duration::from_millis(opts.max_rtt),
+    );
+
+    /* the receiver counts the replies either way, but only the
+     * adaptive mode has the pacer go by them */
+    let feedback = Arc::new(Feedback::default());
+    let pacer = Pacer::new(
+        opts.batch_size,
+        opts.nap_after_batch,
+        opts.rate,
+        opts.burst,
+        opts.max_bandwidth.map(|bandwidth| bandwidth.0),
+        opts.adaptive.then(|| feedback.clone()),
+    );
+
     if let Err(e) = run(
-        &opts.target,
+        &opts.targets,
         &opts.ports.unwrap_or_default(),
         &opts.ranges.unwrap_or_default(),
         opts.flying_tasks,
         opts.max_retries,
         opts.batch_size,
         opts.nap_after_spawn,
-        opts.nap_after_batch,
+        pacer,
+        rtt,
+        feedback,
+        opts.scan_type,
+        opts.connect_timeout,
+        opts.skip_discovery,
+        opts.seed.unwrap_or_else(rand::random),
+        opts.interface,
+        opts.source_ip,
+        opts.source_port.unwrap_or_default(),
+        opts.output_format,
     )
     .await
     {
@@ -45,75 +97,285 @@ async fn main() {
 #[instrument(skip_all, name = "main thread")]
 #[allow(clippy::too_many_arguments)]
 async fn run(
-    target: &str,
+    targets: &[Target],
     ports: &[Port],
     ranges: &[PortRange],
     flying_tasks: u16,
     max_retries: usize,
     batch_size: usize,
     nap_after_spawn: f64,
-    nap_after_batch: f64,
+    pacer: Pacer,
+    rtt: RttEstimator,
+    feedback: Arc<Feedback>,
+    scan_type: ScanType,
+    connect_timeout: u64,
+    skip_discovery: bool,
+    seed: u64,
+    interface: Option<String>,
+    source_ip: Option<IpAddr>,
+    source_ports: SourcePorts,
+    output_format: OutputFormat,
 ) -> Result<()> {
-    let ip_addr = match get_default_gateway_interface()? {
-        IpAddr::V4(ipv4) => ipv4,
-        _ => unimplemented!(),
+    let started = SystemTime::now();
+
+    /* Without cap_net_raw, a SYN scan can still be approximated with full
+     * handshakes, but the other scan types can't, so they bail instead. */
+    let scan_type = match scan_type.needs_raw_sockets() && !raw_sockets_available() {
+        true if scan_type == ScanType::Syn => {
+            eprintln!("tsunami: can't create raw sockets, falling back to a connect scan");
+            ScanType::Connect
+        }
+        true => bail!("{scan_type:?} scans need raw sockets (cap_net_raw)"),
+        false => scan_type,
+    };
+
+    if scan_type == ScanType::Connect
+        && (interface.is_some() || source_ip.is_some() || source_ports != SourcePorts::default())
+    {
+        eprintln!("tsunami: connect scans ignore --interface, --source-ip and --source-port");
+    }
+
+    let hosts = resolve_targets(targets).await?;
+
+    debug!("resolved {} hosts", hosts.len());
+    let targets = hosts.len();
+
+    /* the IP protocol scan reads ports as protocol numbers, and IPv6 has no
+     * single next header field to iterate over once extension headers are in */
+    if scan_type == ScanType::IpProto {
+        if hosts.iter().any(IpAddr::is_ipv6) {
+            bail!("IP protocol scans are IPv4 only");
+        }
+
+        if ports
+            .iter()
+            .chain(ranges.iter().map(|r| &r.end))
+            .any(|p| *p > u8::MAX as Port)
+        {
+            bail!("IP protocol scans take protocol numbers (0-255) as ports");
+        }
+    }
+
+    /* Each host gets the source address of the route the kernel would send
+     * its probes over (through --interface, if given), unless --source-ip
+     * says otherwise, and the ones there's no route to are left out. */
+    let routing_table = RoutingTable::read()?;
+    if let Some(interface) = interface.as_deref() {
+        if !routing_table.has_interface(interface) {
+            bail!("there's no interface named {interface}");
+        }
+    }
+
+    let mut src_ip_addrs = HashMap::new();
+    for host in &hosts {
+        match (routing_table.lookup(*host, interface.as_deref()), source_ip) {
+            (Ok(_), Some(source_ip)) if source_ip.is_ipv4() != host.is_ipv4() => {
+                eprintln!("tsunami: {host} can't be reached from {source_ip}, skipping it")
+            }
+            (Ok(route), source_ip) => {
+                debug!(%host, ?route, "looked up the route");
+                src_ip_addrs.insert(*host, source_ip.unwrap_or(route.src_ip_addr));
+            }
+            (Err(e), _) => eprintln!("tsunami: {e}, skipping {host}"),
+        }
+    }
+    let hosts: Vec<_> = hosts
+        .into_iter()
+        .filter(|host| src_ip_addrs.contains_key(host))
+        .collect();
+
+    /* The key for the sequence number cookies, shared by the transmitter
+     * that sends the probes and the receiver that validates the replies. */
+    let key = RandomState::new();
+
+    /* Most addresses in a range tend to be dead, so ping them all first
+     * and only scan the ones that answer. */
+    let (hosts, mac_addrs, up) = match skip_discovery {
+        true => {
+            let up = hosts
+                .iter()
+                .map(|host| (*host, HostReason::UserSet))
+                .collect();
+            (hosts, HashMap::new(), up)
+        }
+        false if !raw_sockets_available() => {
+            eprintln!("tsunami: can't ping without raw sockets, skipping host discovery");
+            let up = hosts
+                .iter()
+                .map(|host| (*host, HostReason::UserSet))
+                .collect();
+            (hosts, HashMap::new(), up)
+        }
+        false => {
+            let up = discover(
+                &hosts,
+                &src_ip_addrs,
+                &key,
+                max_retries,
+                &rtt,
+                &pacer,
+                source_ports,
+                interface.as_deref(),
+            )
+            .await?;
+            eprintln!("tsunami: {} of {} hosts are up", up.len(), hosts.len());
+
+            /* hosts found over ARP come with their MAC address */
+            let mac_addrs = up
+                .iter()
+                .filter_map(|(host, (_, mac))| mac.map(|mac| (*host, mac)))
+                .collect();
+
+            let hosts = hosts
+                .into_iter()
+                .filter(|host| up.contains_key(host))
+                .collect();
+
+            let up = up
+                .into_iter()
+                .map(|(host, (reason, _))| (host, reason))
+                .collect();
+
+            (hosts, mac_addrs, up)
+        }
     };
 
-    debug!("obtained default gateway interface ip: {:?}", ip_addr);
+    let mut output = output_format.writer(scan_type, started, Hosts { total: targets, up });
 
-    let combined: HashSet<_> = ports
+    /* nothing to scan, but a pipeline reading the results still gets a summary */
+    if hosts.is_empty() {
+        return output.finish(&HashMap::new(), &HashMap::new());
+    }
+
+    let ports: BTreeSet<_> = ports
         .iter()
         .copied()
-        .chain(ranges.iter().flat_map(|r| (r.start..=r.end)))
+        .chain(ranges.iter().flat_map(|r| r.start..=r.end))
+        .collect();
+
+    /* the (host, port) index space, in a fixed order, for the
+     * permutation to walk the same way every time it's seeded the same */
+    let combined: Vec<_> = hosts
+        .iter()
+        .flat_map(|host| ports.iter().map(move |port| (*host, *port)))
         .collect();
 
     info!(
-        "initiating inspection for {} ({} ports) - mr: {} - bs: {} - nas: {} - nab: {}",
-        target,
-        combined.len(),
+        "initiating {:?} inspection for {} hosts ({} ports each) - mr: {} - bs: {} - nas: {} - seed: {} - sp: {:?} - pacer: {:?}",
+        scan_type,
+        hosts.len(),
+        ports.len(),
         max_retries,
         batch_size,
         nap_after_spawn,
-        nap_after_batch,
+        seed,
+        source_ports,
+        pacer,
     );
 
-    /* receiver2mainthread */
-    let (tx, mut rx) = mpsc::channel(8);
+    /* receiver2mainthread; unbounded, so that the receiver never stops
+     * listening while the main thread works through the payloads */
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    /* mainthread2receiver, for when each probe went out */
+    let (sent_tx, sent_rx) = mpsc::unbounded_channel();
+
+    /* workers2receiver, for the connect scan only */
+    let (verdicts_tx, verdicts_rx) = match scan_type {
+        ScanType::Connect => {
+            let (verdicts_tx, verdicts_rx) = mpsc::channel(flying_tasks as usize);
+            (Some(verdicts_tx), Some(verdicts_rx))
+        }
+        _ => (None, None),
+    };
 
     debug!("spawning receiver");
-    let receiver = tokio::spawn(receive(combined, tx, max_retries));
+    let receiver = tokio::spawn(receive(
+        combined,
+        src_ip_addrs.clone(),
+        mac_addrs,
+        key.clone(),
+        scan_type,
+        tx,
+        sent_rx,
+        verdicts_rx,
+        max_retries,
+        rtt,
+        feedback,
+        source_ports,
+        interface.clone(),
+        output,
+    ));
 
-    /* This semaphore controls the maximum number of tasks in flight. */
+    /* Raw scans send all of their probes from here, over a single socket per
+     * address family, whereas connect scans need a task per probe, and this
+     * semaphore controls the maximum number of those in flight. */
+    let transmitter = match verdicts_tx {
+        Some(_) => None,
+        None => Some(Transmitter::new(
+            src_ip_addrs,
+            key,
+            scan_type,
+            seed,
+            source_ports,
+            interface.as_deref(),
+        )?),
+    };
+    let mut pacer = pacer;
     let semaphore = Arc::new(Semaphore::new(flying_tasks as usize));
 
     /* The main thread awaits messages from the receiver.
-     * A message contains a Vec<Port> payload that tells the main thread
-     * which ports to inspect. The ports are not dispatched immediately,
-     * but are first sliced into 'batch_size' sized chunks (for rate limiting). */
+     * A message contains a Vec<Probe> payload that tells the main thread
+     * which (host, port) pairs to inspect. The probes go out at the pace
+     * the pacer sets, and the receiver is told when each one did, in order
+     * to time it out on its own. */
     while let Some(msg) = rx.recv().await {
         match msg {
             Message::Payload(payload) => {
                 debug!("got payload of size {}", payload.len());
 
-                for chunk in payload.chunks(batch_size) {
-                    let mut tasks = vec![];
-
-                    for port in chunk {
-                        tasks.push(tokio::spawn(inspect(
-                            to_ipaddr(target).await?,
-                            *port,
-                            semaphore.clone(),
-                            ip_addr,
-                            nap_after_spawn,
-                        )));
+                match (&transmitter, &verdicts_tx) {
+                    (Some(transmitter), _) => {
+                        transmitter.transmit(&payload, &mut pacer, &sent_tx).await?
                     }
+                    (None, Some(verdicts_tx)) => {
+                        let permutation = Permutation::new(payload.len() as u64, seed);
+                        let payload: Vec<Probe> = permutation
+                            .iter()
+                            .map(|index| payload[index as usize])
+                            .collect();
 
-                    for task in tasks {
-                        task.await??;
-                    }
+                        for chunk in payload.chunks(batch_size) {
+                            let mut tasks = vec![];
+
+                            for (host, port) in chunk {
+                                tasks.push(tokio::spawn(connect(
+                                    *host,
+                                    *port,
+                                    semaphore.clone(),
+                                    verdicts_tx.clone(),
+                                    connect_timeout,
+                                    nap_after_spawn,
+                                )));
 
-                    /* Sleep a little after the sent batch, for good measure. */
-                    sleep(Duration::from_secs_f64(nap_after_batch / 1000.0)).await;
+                                /* the kernel builds the SYN, which is about this big */
+                                pacer.tick((IP_HDR_LEN + TCP_HDR_LEN) as usize).await;
+                            }
+
+                            for task in tasks {
+                                task.await??;
+                            }
+
+                            /* the workers wait for the replies themselves, so the
+                             * receiver only starts the clock once they're done
+                             * (if it's gone, awaiting it tells why) */
+                            let now = Instant::now();
+                            for probe in chunk {
+                                let _ = sent_tx.send((*probe, now));
+                            }
+                        }
+                    }
+                    (None, None) => unreachable!("connect scans always have a verdicts channel"),
                 }
 
                 debug!("dispatched the entire payload of size {}", payload.len());
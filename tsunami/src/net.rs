@@ -1,28 +1,67 @@
-use crate::{error_and_bail, Port};
-use anyhow::{bail, Result};
+use crate::{cli::Target, error_and_bail, Port};
+use anyhow::{bail, Context, Result};
 use libc::{
-    addrinfo, freeaddrinfo, gai_strerror, getaddrinfo, getnameinfo, sockaddr, socklen_t, AF_INET,
+    addrinfo, freeaddrinfo, gai_strerror, getaddrinfo, getnameinfo, sockaddr, socklen_t, AF_UNSPEC,
     NI_MAXHOST, NI_NUMERICHOST,
 };
-use pnet::packet::{
-    ip::IpNextHeaderProtocols,
-    ipv4::{Ipv4Flags, MutableIpv4Packet},
-    tcp::{MutableTcpPacket, TcpFlags},
+use pnet::{
+    ipnetwork::IpNetwork,
+    packet::{
+        arp::{ArpHardwareTypes, ArpOperations, MutableArpPacket},
+        ethernet::{EtherTypes, MutableEthernetPacket},
+        icmpv6::Icmpv6Packet,
+        ip::IpNextHeaderProtocol,
+        ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet},
+        ipv6::MutableIpv6Packet,
+        tcp::{MutableTcpPacket, TcpFlags},
+        udp::MutableUdpPacket,
+        MutablePacket,
+    },
+    util::MacAddr,
+};
+use raw_socket::{
+    tokio::prelude::{Level, Name, RawSocket},
+    Domain, Protocol, Type,
 };
-use raw_socket::{tokio::prelude::RawSocket, Domain, Protocol, Type};
 use std::{
+    collections::{hash_map::RandomState, HashSet},
     ffi::{CStr, CString},
-    net::{IpAddr, Ipv4Addr},
+    hash::BuildHasher,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
 };
+use tokio::time::{sleep, Duration};
+use tracing::debug;
 
 pub const IP_HDR_LEN: u16 = 20;
+pub const IPV6_HDR_LEN: u16 = 40;
 pub const TCP_HDR_LEN: u16 = 20;
+pub const UDP_HDR_LEN: u16 = 8;
+pub const SCTP_HDR_LEN: u16 = 12;
+pub const SCTP_INIT_LEN: u16 = 20;
+pub const ETHERNET_HDR_LEN: u16 = 14;
+pub const ARP_LEN: u16 = 28;
 pub const IPPROTO_RAW: i32 = 255;
+pub const SOURCE_PORT: Port = 0x1337;
+
+/// How many times a probe is sent again when the kernel runs out of
+/// buffer space for it, before it's left for the receiver to retry.
+const ENOBUFS_RETRIES: usize = 3;
+
+/// How much the kernel may buffer for each of the receive sockets.
+pub const RECV_BUF_SIZE: i32 = 16 << 20;
+
+/// How much of a packet we read at once: the largest an IP packet can be,
+/// since a read that's too short truncates the packet, which then can't
+/// be told apart from a malformed one.
+pub const MAX_PACKET_LEN: usize = 65535;
+
+/// Upper bound on the number of hosts a single target may expand to.
+pub const MAX_HOSTS_PER_TARGET: u128 = 1 << 24;
 
 pub async fn dns_lookup(hostname: &str) -> Result<IpAddr> {
     /* prepare the hints for the getaddrinfo call */
     let hints = addrinfo {
-        ai_family: AF_INET,
+        ai_family: AF_UNSPEC,
         ai_socktype: 0,
         ai_protocol: 0,
         ai_flags: 0,
@@ -76,104 +115,533 @@ pub async fn dns_lookup(hostname: &str) -> Result<IpAddr> {
     Ok(c_str.to_str()?.to_string().parse::<IpAddr>()?)
 }
 
-pub fn build_ipv4_packet(buf: &mut [u8], dest: Ipv4Addr) -> MutableIpv4Packet {
+/// Builds the IPv4 header in front of a payload of `buf.len() - IP_HDR_LEN` bytes.
+pub fn build_ipv4_packet(
+    buf: &mut [u8],
+    src: Ipv4Addr,
+    dest: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+) -> MutableIpv4Packet<'_> {
     use pnet::packet::ipv4::checksum;
 
+    let total_length = buf.len() as u16;
     let mut packet = MutableIpv4Packet::new(buf).unwrap();
 
     packet.set_version(4);
     packet.set_ttl(u8::MAX);
     packet.set_header_length(5); /* n * 32 bits. */
     packet.set_identification(rand::random::<u16>());
-    packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    packet.set_next_level_protocol(protocol);
+    packet.set_source(src);
     packet.set_destination(dest);
     packet.set_flags(Ipv4Flags::DontFragment);
-    packet.set_total_length(IP_HDR_LEN + TCP_HDR_LEN);
+    packet.set_total_length(total_length);
     packet.set_checksum(checksum(&packet.to_immutable()));
 
     packet
 }
 
+/// Builds the IPv6 header in front of a payload of `buf.len() - IPV6_HDR_LEN` bytes.
+pub fn build_ipv6_packet(
+    buf: &mut [u8],
+    src: Ipv6Addr,
+    dest: Ipv6Addr,
+    protocol: IpNextHeaderProtocol,
+) -> MutableIpv6Packet<'_> {
+    let payload_length = buf.len() as u16 - IPV6_HDR_LEN;
+    let mut packet = MutableIpv6Packet::new(buf).unwrap();
+
+    /* unlike IPv4, the kernel won't fill in the source address for us */
+    packet.set_version(6);
+    packet.set_hop_limit(u8::MAX);
+    packet.set_next_header(protocol);
+    packet.set_source(src);
+    packet.set_destination(dest);
+    packet.set_payload_length(payload_length);
+
+    packet
+}
+
+/// Derives the sequence number of a probe from a keyed hash of its 4-tuple,
+/// so that replies can be validated without keeping any per-probe state.
+pub fn sequence_number(
+    key: &RandomState,
+    src_ip_addr: IpAddr,
+    src_port: Port,
+    dest: IpAddr,
+    port: Port,
+) -> u32 {
+    key.hash_one((src_ip_addr, src_port, dest, port)) as u32
+}
+
+/// The source ports the probes go out from: `SOURCE_PORT` unless told
+/// otherwise, or a range of ports, in which case each probe gets one of its
+/// own. That one is derived from the cookie key too, so a reply can be told
+/// to be addressed to the right port without remembering which it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcePorts {
+    pub start: Port,
+    pub end: Port,
+}
+
+impl SourcePorts {
+    /// The source port of the probe to the port.
+    pub fn pick(&self, key: &RandomState, src_ip_addr: IpAddr, dest: IpAddr, port: Port) -> Port {
+        match self.end - self.start {
+            0 => self.start,
+            span => {
+                let hash = key.hash_one((dest, port, src_ip_addr));
+                self.start + (hash % (span as u64 + 1)) as Port
+            }
+        }
+    }
+}
+
+impl Default for SourcePorts {
+    fn default() -> Self {
+        Self {
+            start: SOURCE_PORT,
+            end: SOURCE_PORT,
+        }
+    }
+}
+
+impl std::str::FromStr for SourcePorts {
+    type Err = anyhow::Error;
+
+    /// Takes a port (53), a range of ports (40000-40999) or "random", which
+    /// spreads the probes over all of the unprivileged ones.
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = match s.split_once('-') {
+            _ if s == "random" => (1024, Port::MAX),
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None => (s.parse()?, s.parse()?),
+        };
+
+        if start > end {
+            bail!("source port range start {start} is past range end {end}");
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
 pub fn build_tcp_packet(
     buf: &mut [u8],
-    destination: Ipv4Addr,
+    destination: IpAddr,
     port: Port,
-    src_ip_addr: Ipv4Addr,
-) -> Result<MutableTcpPacket> {
-    use pnet::packet::tcp::ipv4_checksum;
+    src_ip_addr: IpAddr,
+    src_port: Port,
+    sequence: u32,
+    flags: u8,
+) -> Result<MutableTcpPacket<'_>> {
+    use pnet::packet::tcp::{ipv4_checksum, ipv6_checksum};
 
     let mut packet = MutableTcpPacket::new(buf).unwrap();
-    packet.set_source(0x1337_u16);
+    packet.set_source(src_port);
     packet.set_destination(port);
-    packet.set_sequence(rand::random::<u32>());
+    packet.set_sequence(sequence);
     packet.set_data_offset(5);
-    packet.set_flags(TcpFlags::SYN);
+    packet.set_flags(flags);
+    if flags & TcpFlags::ACK != 0 {
+        /* a RST in reply takes its sequence number from this field */
+        packet.set_acknowledgement(sequence);
+    }
     packet.set_window(0x7110_u16);
-    packet.set_checksum(ipv4_checksum(
-        &packet.to_immutable(),
-        &src_ip_addr,
-        &destination,
-    ));
+
+    /* the checksum covers the pseudo-header, which differs per family */
+    let checksum = match (src_ip_addr, destination) {
+        (IpAddr::V4(src), IpAddr::V4(dest)) => ipv4_checksum(&packet.to_immutable(), &src, &dest),
+        (IpAddr::V6(src), IpAddr::V6(dest)) => ipv6_checksum(&packet.to_immutable(), &src, &dest),
+        _ => bail!("address family mismatch between {src_ip_addr} and {destination}"),
+    };
+    packet.set_checksum(checksum);
 
     Ok(packet)
 }
 
-pub fn create_recv_sock() -> Result<RawSocket> {
-    match RawSocket::new(Domain::ipv4(), Type::raw(), Protocol::tcp().into()) {
-        Ok(sock) => Ok(sock),
+pub fn build_udp_packet<'a>(
+    buf: &'a mut [u8],
+    destination: IpAddr,
+    port: Port,
+    src_ip_addr: IpAddr,
+    src_port: Port,
+    payload: &[u8],
+) -> Result<MutableUdpPacket<'a>> {
+    use pnet::packet::udp::{ipv4_checksum, ipv6_checksum};
+
+    let mut packet = MutableUdpPacket::new(buf).unwrap();
+    packet.set_source(src_port);
+    packet.set_destination(port);
+    packet.set_length(UDP_HDR_LEN + payload.len() as u16);
+    packet.set_payload(payload);
+
+    /* the checksum covers the pseudo-header, which differs per family */
+    let checksum = match (src_ip_addr, destination) {
+        (IpAddr::V4(src), IpAddr::V4(dest)) => ipv4_checksum(&packet.to_immutable(), &src, &dest),
+        (IpAddr::V6(src), IpAddr::V6(dest)) => ipv6_checksum(&packet.to_immutable(), &src, &dest),
+        _ => bail!("address family mismatch between {src_ip_addr} and {destination}"),
+    };
+    packet.set_checksum(checksum);
+
+    Ok(packet)
+}
+
+pub fn create_recv_sock(
+    domain: Domain,
+    protocol: Protocol,
+    interface: Option<&str>,
+) -> Result<RawSocket> {
+    let sock = match RawSocket::new(domain, Type::raw(), protocol.into()) {
+        Ok(sock) => sock,
         Err(_) => error_and_bail!("couldn't create the socket"),
+    };
+    bind_to_interface(&sock, interface)?;
+
+    /* The replies come in bursts as large as the bursts of probes, which
+     * overflow the default buffer long before the receiver falls behind.
+     * SO_RCVBUFFORCE gets past net.core.rmem_max, but needs CAP_NET_ADMIN,
+     * otherwise SO_RCVBUF settles for rmem_max. */
+    if sock
+        .set_sockopt(
+            Level::SOCKET,
+            Name::from(libc::SO_RCVBUFFORCE),
+            &RECV_BUF_SIZE,
+        )
+        .is_err()
+    {
+        sock.set_sockopt(Level::SOCKET, Name::SO_RCVBUF, &RECV_BUF_SIZE)?;
     }
+
+    Ok(sock)
+}
+
+/// Builds an ICMP or ICMPv6 query of `buf.len()` bytes: an echo request, or
+/// a timestamp request if there's room for the three timestamps, which we
+/// leave zeroed. The reply echoes both `ident` and `seq` back to us.
+pub fn build_icmp_packet(
+    buf: &mut [u8],
+    icmp_type: u8,
+    ident: u16,
+    seq: u16,
+    src_ip_addr: IpAddr,
+    dest: IpAddr,
+) {
+    buf[0] = icmp_type;
+    buf[1] = 0; /* code */
+    buf[2..4].copy_from_slice(&0u16.to_be_bytes());
+    buf[4..6].copy_from_slice(&ident.to_be_bytes());
+    buf[6..8].copy_from_slice(&seq.to_be_bytes());
+    buf[8..].fill(0);
+
+    /* unlike ICMP, ICMPv6 covers a pseudo-header in its checksum */
+    let checksum = match (src_ip_addr, dest) {
+        (IpAddr::V6(src), IpAddr::V6(dest)) => {
+            pnet::packet::icmpv6::checksum(&Icmpv6Packet::new(buf).unwrap(), &src, &dest)
+        }
+        _ => pnet::util::checksum(buf, 1),
+    };
+    buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Builds a broadcast Ethernet frame carrying an ARP request that asks
+/// who has `dest`, on behalf of `src_mac` and `src_ip_addr`.
+pub fn build_arp_request(
+    buf: &mut [u8],
+    src_mac: MacAddr,
+    src_ip_addr: Ipv4Addr,
+    dest: Ipv4Addr,
+) -> MutableEthernetPacket<'_> {
+    let mut frame = MutableEthernetPacket::new(buf).unwrap();
+
+    frame.set_destination(MacAddr::broadcast());
+    frame.set_source(src_mac);
+    frame.set_ethertype(EtherTypes::Arp);
+
+    let mut arp_packet = MutableArpPacket::new(frame.payload_mut()).unwrap();
+
+    arp_packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp_packet.set_protocol_type(EtherTypes::Ipv4);
+    arp_packet.set_hw_addr_len(6);
+    arp_packet.set_proto_addr_len(4);
+    arp_packet.set_operation(ArpOperations::Request);
+    arp_packet.set_sender_hw_addr(src_mac);
+    arp_packet.set_sender_proto_addr(src_ip_addr);
+    arp_packet.set_target_hw_addr(MacAddr::zero());
+    arp_packet.set_target_proto_addr(dest);
+
+    frame
 }
 
-pub fn create_send_sock() -> Result<RawSocket> {
-    match RawSocket::new(
+/// Builds an SCTP packet carrying a single INIT chunk. The initiate tag
+/// plays the part of the TCP sequence number: the peer echoes it as the
+/// verification tag of its INIT-ACK or ABORT, so it carries the cookie.
+pub fn build_sctp_packet(buf: &mut [u8], src_port: Port, port: Port, initiate_tag: u32) {
+    /* common header: ports, verification tag (zero for INIT), checksum */
+    buf[0..2].copy_from_slice(&src_port.to_be_bytes());
+    buf[2..4].copy_from_slice(&port.to_be_bytes());
+    buf[4..8].copy_from_slice(&0u32.to_be_bytes());
+    buf[8..12].copy_from_slice(&0u32.to_be_bytes());
+
+    /* INIT chunk: type, flags, length, then the INIT parameters */
+    let chunk = &mut buf[SCTP_HDR_LEN as usize..];
+    chunk[0] = 1;
+    chunk[1] = 0;
+    chunk[2..4].copy_from_slice(&SCTP_INIT_LEN.to_be_bytes());
+    chunk[4..8].copy_from_slice(&initiate_tag.to_be_bytes());
+    chunk[8..12].copy_from_slice(&0x7110_u32.to_be_bytes()); /* a_rwnd */
+    chunk[12..14].copy_from_slice(&1u16.to_be_bytes()); /* outbound streams */
+    chunk[14..16].copy_from_slice(&1u16.to_be_bytes()); /* inbound streams */
+    chunk[16..20].copy_from_slice(&initiate_tag.to_be_bytes()); /* initial TSN */
+
+    /* unlike everything else on the wire, the CRC32c goes in little-endian */
+    let checksum = crc32c(buf);
+    buf[8..12].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// CRC32c (Castagnoli), as used by SCTP (RFC 9260, appendix A).
+pub fn crc32c(data: &[u8]) -> u32 {
+    const POLY: u32 = 0x82f6_3b78; /* reversed 0x1edc6f41 */
+
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ POLY,
+                _ => crc >> 1,
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Updates an internet checksum for a 16-bit word of the data it covers
+/// changing from `old` to `new`, without going over the rest of the data
+/// again (RFC 1624, eqn. 3: HC' = ~(~HC + ~m + m')).
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = !checksum as u32 + !old as u32 + new as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Tells whether we're allowed to create raw sockets, which takes
+/// `cap_net_raw`, without making a fuss if we aren't.
+pub fn raw_sockets_available() -> bool {
+    RawSocket::new(
         Domain::ipv4(),
         Type::raw(),
         Protocol::from(IPPROTO_RAW).into(),
-    ) {
-        Ok(sock) => Ok(sock),
+    )
+    .is_ok()
+}
+
+/// Sends the packet, backing off for a bit whenever the kernel runs out of
+/// buffer space, which it does when the probes go out faster than the
+/// interface can take them. If it doesn't clear up, the probe is dropped
+/// and left for the receiver to retry, just like one lost on the wire.
+/// So are the probes the kernel refuses to send to this particular
+/// destination (a broadcast address, say), so that one of them doesn't
+/// take the whole scan down with it.
+pub async fn send_to(sock: &RawSocket, packet: &[u8], dest: IpAddr, port: Port) -> Result<()> {
+    for _ in 0..ENOBUFS_RETRIES {
+        match sock.send_to(packet, (dest, port)).await {
+            Ok(_) => return Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                sleep(Duration::from_millis(1)).await;
+            }
+            Err(e) => {
+                debug!(%dest, port, "couldn't send the probe: {e}");
+                return Ok(());
+            }
+        }
+    }
+
+    debug!(%dest, port, "out of buffer space, dropping the probe");
+
+    Ok(())
+}
+
+/// Receives on the socket if there is one, otherwise never resolves,
+/// so that it can sit in a `select!` next to the other sockets.
+pub async fn recv_on(
+    sock: Option<&RawSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match sock {
+        Some(sock) => sock.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Creates an IPPROTO_RAW socket, which implies that we'll be
+/// supplying the IP header ourselves, for both IPv4 and IPv6.
+pub fn create_send_sock(domain: Domain, interface: Option<&str>) -> Result<RawSocket> {
+    let sock = match RawSocket::new(domain, Type::raw(), Protocol::from(IPPROTO_RAW).into()) {
+        Ok(sock) => sock,
         Err(_) => error_and_bail!("couldn't create the socket"),
+    };
+    bind_to_interface(&sock, interface)?;
+
+    Ok(sock)
+}
+
+/// Ties the socket to the interface given with --interface, if any, since
+/// the kernel would otherwise send the probes wherever its routes point.
+fn bind_to_interface(sock: &RawSocket, interface: Option<&str>) -> Result<()> {
+    if let Some(interface) = interface {
+        sock.bind_device(interface)
+            .with_context(|| format!("binding to {interface}"))?;
     }
+
+    Ok(())
 }
 
-pub async fn to_ipaddr(target: &str) -> Result<Ipv4Addr> {
-    match target.parse::<Ipv4Addr>() {
+pub async fn to_ipaddr(target: &str) -> Result<IpAddr> {
+    match target.parse::<IpAddr>() {
         Ok(addr) => Ok(addr),
         Err(_) => match dns_lookup(target).await {
-            Ok(ip_addr) => match ip_addr {
-                IpAddr::V4(addr) => Ok(addr),
-                IpAddr::V6(_) => bail!("not implemented for ipv6."),
-            },
+            Ok(ip_addr) => Ok(ip_addr),
             Err(_) => error_and_bail!("couldn't resolve the hostname {target}"),
         },
     }
 }
 
-pub fn get_default_gateway_interface() -> Result<IpAddr> {
-    use pnet::datalink;
-    use std::process::Command;
+/// Expands the targets given on the command line into a deduplicated,
+/// ordered list of host addresses.
+pub async fn resolve_targets(targets: &[Target]) -> Result<Vec<IpAddr>> {
+    let mut hosts = vec![];
 
-    let output = String::from_utf8(
-        Command::new("ip")
-            .args(["route", "show", "default"])
-            .output()?
-            .stdout,
-    )?;
+    for target in targets {
+        match target {
+            Target::Host(host) => hosts.push(to_ipaddr(host).await?),
+            Target::Network(network) => {
+                /* size() overflows on a /0, so go by the host bits */
+                let host_bits = match network {
+                    IpNetwork::V4(network) => 32 - network.prefix(),
+                    IpNetwork::V6(network) => 128 - network.prefix(),
+                };
+                if u32::from(host_bits) > MAX_HOSTS_PER_TARGET.ilog2() {
+                    bail!("network {network} is too large to scan");
+                }
+                hosts.extend(network.iter());
+            }
+            Target::Range(IpAddr::V4(start), IpAddr::V4(end)) => {
+                if u128::from(u32::from(*end) - u32::from(*start)) >= MAX_HOSTS_PER_TARGET {
+                    bail!("range {start}-{end} is too large to scan");
+                }
+                hosts.extend((u32::from(*start)..=u32::from(*end)).map(|ip| IpAddr::V4(ip.into())))
+            }
+            Target::Range(IpAddr::V6(start), IpAddr::V6(end)) => {
+                if u128::from(*end) - u128::from(*start) >= MAX_HOSTS_PER_TARGET {
+                    bail!("range {start}-{end} is too large to scan");
+                }
+                hosts
+                    .extend((u128::from(*start)..=u128::from(*end)).map(|ip| IpAddr::V6(ip.into())))
+            }
+            Target::Range(start, end) => bail!("range {start}-{end} mixes address families"),
+        }
+    }
 
-    let default_gateway = output
-        .split_whitespace()
-        .nth(2)
-        .unwrap()
-        .parse::<IpAddr>()?;
+    let mut seen = HashSet::new();
+    hosts.retain(|host| seen.insert(*host));
 
-    let mut interface_ip_addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-    for interface in datalink::interfaces() {
-        for ip_net in interface.ips {
-            if ip_net.contains(default_gateway) {
-                interface_ip_addr = ip_net.ip();
-            }
+    Ok(hosts)
+}
+
+/// Lets the kernel pick the IPv6 source address it would use to reach
+/// `dest`. Connecting a UDP socket doesn't put anything on the wire.
+pub fn get_ipv6_source_addr(dest: Ipv6Addr) -> Result<Ipv6Addr> {
+    let sock = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?;
+    sock.connect((dest, 9))?;
+
+    match sock.local_addr()?.ip() {
+        IpAddr::V6(addr) => Ok(addr),
+        IpAddr::V4(_) => error_and_bail!("no IPv6 source address for {dest}"),
+    }
+}
+
+/// Validates the IPv4 header of a received packet and returns its source
+/// address along with the payload it carries. The header length is
+/// taken from IHL, since replies may carry IP options.
+pub fn ipv4_payload(packet: &[u8], protocol: IpNextHeaderProtocol) -> Option<(Ipv4Addr, &[u8])> {
+    let ipv4_packet = Ipv4Packet::new(packet)?;
+
+    let header_len = ipv4_packet.get_header_length() as usize * 4;
+    let total_len = ipv4_packet.get_total_length() as usize;
+
+    if header_len < IP_HDR_LEN as usize || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+
+    if ipv4_packet.get_next_level_protocol() != protocol {
+        return None;
+    }
+
+    Some((ipv4_packet.get_source(), &packet[header_len..total_len]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32c, resolve_targets, update_checksum};
+    use crate::cli::Target;
+    use std::net::IpAddr;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    /* RFC 1624, section 4: the checksum of a header whose word changes
+     * from 0x5555 to 0x3285 goes from 0xdd2f to 0x0000, not 0xffff */
+    #[test]
+    fn update_checksum_rfc1624() {
+        assert_eq!(update_checksum(0xdd2f, 0x5555, 0x3285), 0x0000);
+        assert_eq!(update_checksum(0x1234, 0xabcd, 0xabcd), 0x1234);
+    }
+
+    #[tokio::test]
+    async fn resolve_targets_rejects_zero_prefix() {
+        for network in ["0.0.0.0/0", "::/0", "10.0.0.0/7", "2001:db8::/103"] {
+            let target = network.parse::<Target>().unwrap();
+            assert!(resolve_targets(&[target]).await.is_err(), "{network}");
         }
+
+        let target = "10.0.0.0/30".parse::<Target>().unwrap();
+        assert_eq!(resolve_targets(&[target]).await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn resolve_targets_expands_and_deduplicates() {
+        let targets = ["10.0.0.3", "10.0.0.1-4", "10.0.0.2/31", "::1", "::1-::2"]
+            .map(|target| target.parse::<Target>().unwrap());
+        let hosts = resolve_targets(&targets).await.unwrap();
+
+        let expected = ["10.0.0.3", "10.0.0.1", "10.0.0.2", "10.0.0.4", "::1", "::2"];
+        assert_eq!(hosts, expected.map(|host| host.parse::<IpAddr>().unwrap()));
     }
 
-    Ok(interface_ip_addr)
+    #[tokio::test]
+    async fn resolve_targets_rejects_oversized_ranges() {
+        for range in [
+            "0.0.0.0-255.255.255.255",
+            "10.0.0.0-11.0.0.0",
+            "::-ffff::",
+            "2001:db8::-2001:db8::100:0",
+        ] {
+            let target = range.parse::<Target>().unwrap();
+            assert!(resolve_targets(&[target]).await.is_err(), "{range}");
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_targets_rejects_mixed_ranges() {
+        let target = Target::Range("10.0.0.1".parse().unwrap(), "::1".parse().unwrap());
+        assert!(resolve_targets(&[target]).await.is_err());
+    }
 }
@@ -0,0 +1,83 @@
+use pnet::util::MacAddr;
+use std::{collections::HashMap, fs, sync::OnceLock};
+
+/// Where the usual packages put a copy of the IEEE OUI registry (ieee-data,
+/// hwdata) or nmap's digest of it. The first one that's there gets used.
+const OUI_DATABASES: [&str; 4] = [
+    "/usr/share/ieee-data/oui.txt",
+    "/usr/share/hwdata/oui.txt",
+    "/usr/share/misc/oui.txt",
+    "/usr/share/nmap/nmap-mac-prefixes",
+];
+
+/* A handful of prefixes to fall back on when there's no database around,
+ * mostly the virtual NICs that tend to show up on a lab segment. */
+const BUILTIN: [([u8; 3], &str); 12] = [
+    ([0x00, 0x05, 0x69], "VMware"),
+    ([0x00, 0x0c, 0x29], "VMware"),
+    ([0x00, 0x50, 0x56], "VMware"),
+    ([0x08, 0x00, 0x27], "Oracle VirtualBox"),
+    ([0x52, 0x54, 0x00], "QEMU virtual NIC"),
+    ([0x00, 0x15, 0x5d], "Microsoft Hyper-V"),
+    ([0x00, 0x16, 0x3e], "Xensource"),
+    ([0x00, 0x1c, 0x42], "Parallels"),
+    ([0xb8, 0x27, 0xeb], "Raspberry Pi Foundation"),
+    ([0xdc, 0xa6, 0x32], "Raspberry Pi Trading"),
+    ([0xe4, 0x5f, 0x01], "Raspberry Pi Trading"),
+    ([0x00, 0x00, 0x0c], "Cisco Systems"),
+];
+
+/// Looks up the vendor the MAC address was assigned to, going by its
+/// organizationally unique identifier (the first three bytes).
+pub fn vendor(mac: MacAddr) -> Option<&'static str> {
+    static VENDORS: OnceLock<HashMap<[u8; 3], String>> = OnceLock::new();
+
+    let vendors = VENDORS.get_or_init(|| {
+        OUI_DATABASES
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .map(|contents| contents.lines().filter_map(parse_line).collect())
+            .unwrap_or_default()
+    });
+
+    let oui = [mac.0, mac.1, mac.2];
+
+    match vendors.get(&oui) {
+        Some(vendor) => Some(vendor.as_str()),
+        None => BUILTIN
+            .iter()
+            .find(|(prefix, _)| *prefix == oui)
+            .map(|(_, vendor)| *vendor),
+    }
+}
+
+/// Whether the address was made up locally (by a hypervisor, say, or for
+/// privacy) rather than assigned by a vendor, so that there's no OUI to go by.
+pub fn is_locally_administered(mac: MacAddr) -> bool {
+    mac.0 & 0x02 != 0
+}
+
+/// Parses a line of either the IEEE registry ("00-50-56   (hex)\t\tVMware, Inc.")
+/// or nmap's digest ("005056 VMware"). Everything else is skipped, including
+/// the "(base 16)" lines that repeat each entry of the registry.
+fn parse_line(line: &str) -> Option<([u8; 3], String)> {
+    if line.contains("(base 16)") {
+        return None;
+    }
+
+    let (prefix, vendor) = match line.split_once("(hex)") {
+        Some((prefix, vendor)) => (prefix.trim().replace('-', ""), vendor.trim()),
+        None => {
+            let (prefix, vendor) = line.split_once(char::is_whitespace)?;
+            (prefix.to_string(), vendor.trim())
+        }
+    };
+
+    if prefix.len() != 6 || vendor.is_empty() {
+        return None;
+    }
+
+    let byte = |i: usize| u8::from_str_radix(prefix.get(i..i + 2)?, 16).ok();
+
+    Some(([byte(0)?, byte(2)?, byte(4)?], vendor.to_string()))
+}
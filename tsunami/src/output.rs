@@ -0,0 +1,1046 @@
+use crate::{
+    oui::{is_locally_administered, vendor},
+    scan::ScanType,
+    HostReason, Port, PortInfo, PortStatus, Probe, Reason,
+};
+use anyhow::{bail, Result};
+use pnet::util::MacAddr;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CStr,
+    fmt::Write as _,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The states a host has more than this many ports in are summed up rather
+/// than listed port by port, like nmap does.
+const EXTRAPORTS_THRESHOLD: usize = 25;
+
+/// How the results are written out: as text meant for people, which is
+/// what it's always been, as JSON, either a single document or a record
+/// per line (NDJSON) for tools that read as they go, as nmap's XML, for
+/// the tools that import nmap scans, or as a line per host (grepable) or
+/// per port (CSV), for awk and spreadsheets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Ndjson,
+    Xml,
+    Grepable,
+    Csv,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "xml" => Ok(Self::Xml),
+            "grepable" => Ok(Self::Grepable),
+            "csv" => Ok(Self::Csv),
+            _ => bail!("expected one of text, json, ndjson, xml, grepable, csv"),
+        }
+    }
+}
+
+/// What's known about the targets before their ports get scanned: how many
+/// of them there were, and what told us each of the ones that are up is.
+#[derive(Debug, Clone, Default)]
+pub struct Hosts {
+    pub total: usize,
+    pub up: HashMap<IpAddr, HostReason>,
+}
+
+impl OutputFormat {
+    /// Makes the writer for this format, for a scan of `hosts` that started
+    /// at `started`.
+    pub fn writer(self, scan_type: ScanType, started: SystemTime, hosts: Hosts) -> Box<dyn Output> {
+        match self {
+            Self::Text => Box::new(TextWriter),
+            Self::Json | Self::Ndjson => Box::new(JsonWriter {
+                format: self,
+                scan_type,
+            }),
+            Self::Xml => Box::new(XmlWriter {
+                scan_type,
+                started,
+                hosts,
+            }),
+            Self::Grepable => Box::new(GrepableWriter { scan_type }),
+            Self::Csv => Box::new(CsvWriter { scan_type }),
+        }
+    }
+}
+
+/// Where the results go. The text output tells about the open ports as
+/// they turn up, whereas the other formats write it all out in one go
+/// once the scan is over.
+pub trait Output: Send {
+    /// Tells about a port that was just found open.
+    fn open(&mut self, _host: IpAddr, _port: Port) -> Result<()> {
+        Ok(())
+    }
+
+    /// Writes out the results of the scan, which is over.
+    fn finish(
+        &mut self,
+        status: &HashMap<Probe, PortInfo>,
+        mac_addrs: &HashMap<IpAddr, MacAddr>,
+    ) -> Result<()>;
+}
+
+struct TextWriter;
+
+struct JsonWriter {
+    format: OutputFormat,
+    scan_type: ScanType,
+}
+
+struct XmlWriter {
+    scan_type: ScanType,
+    started: SystemTime,
+    hosts: Hosts,
+}
+
+struct GrepableWriter {
+    scan_type: ScanType,
+}
+
+struct CsvWriter {
+    scan_type: ScanType,
+}
+
+impl Output for TextWriter {
+    fn open(&mut self, host: IpAddr, port: Port) -> Result<()> {
+        writeln!(io::stdout().lock(), "{}: open", SocketAddr::new(host, port))?;
+        Ok(())
+    }
+
+    fn finish(
+        &mut self,
+        status: &HashMap<Probe, PortInfo>,
+        mac_addrs: &HashMap<IpAddr, MacAddr>,
+    ) -> Result<()> {
+        write_text(status, mac_addrs)
+    }
+}
+
+impl Output for JsonWriter {
+    fn finish(
+        &mut self,
+        status: &HashMap<Probe, PortInfo>,
+        mac_addrs: &HashMap<IpAddr, MacAddr>,
+    ) -> Result<()> {
+        let mut stdout = io::BufWriter::new(io::stdout().lock());
+        write_json(&mut stdout, self.format, self.scan_type, status, mac_addrs)?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+impl Output for XmlWriter {
+    fn finish(
+        &mut self,
+        status: &HashMap<Probe, PortInfo>,
+        mac_addrs: &HashMap<IpAddr, MacAddr>,
+    ) -> Result<()> {
+        let mut stdout = io::stdout().lock();
+        write_xml(
+            &mut stdout,
+            self.scan_type,
+            self.started,
+            SystemTime::now(),
+            &self.hosts,
+            status,
+            mac_addrs,
+        )?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+impl Output for GrepableWriter {
+    fn finish(
+        &mut self,
+        status: &HashMap<Probe, PortInfo>,
+        _mac_addrs: &HashMap<IpAddr, MacAddr>,
+    ) -> Result<()> {
+        write_grepable(self.scan_type, status)
+    }
+}
+
+impl Output for CsvWriter {
+    fn finish(
+        &mut self,
+        status: &HashMap<Probe, PortInfo>,
+        _mac_addrs: &HashMap<IpAddr, MacAddr>,
+    ) -> Result<()> {
+        write_csv(io::stdout().lock(), self.scan_type, status)
+    }
+}
+
+/// Some of the ports of a host, along with what became of them.
+type HostPorts<'a> = Vec<(Port, &'a PortInfo)>;
+
+/// What became of a (host, port) pair.
+#[derive(Serialize)]
+struct PortRecord {
+    host: IpAddr,
+    port: Port,
+    protocol: &'static str,
+    status: String,
+    reason: Option<String>,
+    /// How many times the probe was sent again.
+    retries: usize,
+    rtt_ms: Option<f64>,
+    ttl: Option<u8>,
+    window: Option<u16>,
+}
+
+impl PortRecord {
+    /// The names of the fields, which make up the CSV header.
+    const COLUMNS: [&'static str; 9] = [
+        "host", "port", "protocol", "status", "reason", "retries", "rtt_ms", "ttl", "window",
+    ];
+}
+
+#[derive(Serialize, Default)]
+struct Counts {
+    open: usize,
+    closed: usize,
+    filtered: usize,
+    unfiltered: usize,
+    open_filtered: usize,
+    /// The ports whose probes were sent more than once.
+    retried: usize,
+}
+
+impl Counts {
+    fn add(&mut self, info: &PortInfo) {
+        match info.status {
+            PortStatus::Open => self.open += 1,
+            PortStatus::Closed => self.closed += 1,
+            PortStatus::Filtered => self.filtered += 1,
+            PortStatus::Unfiltered => self.unfiltered += 1,
+            PortStatus::OpenFiltered => self.open_filtered += 1,
+            PortStatus::NotInspected => {}
+        }
+
+        if info.retried > 1 {
+            self.retried += 1;
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HostSummary {
+    host: IpAddr,
+    mac: Option<String>,
+    vendor: Option<String>,
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    scan_type: String,
+    hosts: Vec<HostSummary>,
+    #[serde(flatten)]
+    total: Counts,
+}
+
+/// A line of NDJSON, tagged with what it holds.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Line<'a> {
+    Port(&'a PortRecord),
+    Summary(&'a Summary),
+}
+
+/// Writes a record per (host, port) pair, ordered by host and port,
+/// followed by a summary of the scan, in one of the JSON formats.
+fn write_json(
+    out: &mut impl Write,
+    format: OutputFormat,
+    scan_type: ScanType,
+    status: &HashMap<Probe, PortInfo>,
+    mac_addrs: &HashMap<IpAddr, MacAddr>,
+) -> Result<()> {
+    let ports = port_records(scan_type, status);
+
+    let mut total = Counts::default();
+    let mut per_host: BTreeMap<IpAddr, Counts> = BTreeMap::new();
+    for ((host, _), info) in status {
+        total.add(info);
+        per_host.entry(*host).or_default().add(info);
+    }
+
+    let hosts = per_host
+        .into_iter()
+        .map(|(host, counts)| {
+            let mac = mac_addrs.get(&host);
+            HostSummary {
+                host,
+                mac: mac.map(|mac| mac.to_string()),
+                vendor: mac.and_then(|mac| vendor(*mac)).map(str::to_string),
+                counts,
+            }
+        })
+        .collect();
+
+    let summary = Summary {
+        scan_type: scan_type.to_string(),
+        hosts,
+        total,
+    };
+
+    match format {
+        OutputFormat::Json => {
+            #[derive(Serialize)]
+            struct Document<'a> {
+                ports: &'a [PortRecord],
+                summary: &'a Summary,
+            }
+
+            let document = Document {
+                ports: &ports,
+                summary: &summary,
+            };
+            serde_json::to_writer_pretty(&mut *out, &document)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for port in &ports {
+                serde_json::to_writer(&mut *out, &Line::Port(port))?;
+                writeln!(out)?;
+            }
+            serde_json::to_writer(&mut *out, &Line::Summary(&summary))?;
+            writeln!(out)?;
+        }
+        _ => bail!("{format:?} isn't a JSON format"),
+    }
+
+    Ok(())
+}
+
+/// Writes the results the way `nmap -oX` does, as far as tsunami knows
+/// them: the elements that importers go by (nmaprun, host, address, ports,
+/// port, state) with the attributes nmap gives them, and the ports in the
+/// states a host has a lot of them in summed up in `<extraports>`. The
+/// targets that weren't found up are only counted.
+fn write_xml(
+    out: &mut impl Write,
+    scan_type: ScanType,
+    started: SystemTime,
+    finished: SystemTime,
+    hosts: &Hosts,
+    status: &HashMap<Probe, PortInfo>,
+    mac_addrs: &HashMap<IpAddr, MacAddr>,
+) -> Result<()> {
+    let (start, end) = (epoch_secs(started), epoch_secs(finished));
+    let elapsed = finished
+        .duration_since(started)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    let mut per_host: BTreeMap<IpAddr, BTreeMap<Port, &PortInfo>> = BTreeMap::new();
+    for ((host, port), info) in status {
+        per_host.entry(*host).or_default().insert(*port, info);
+    }
+
+    let protocol = scan_type.protocol_name();
+    let mut ports: Vec<Port> = status.keys().map(|(_, port)| *port).collect();
+    ports.sort_unstable();
+    ports.dedup();
+
+    let args: Vec<String> = std::env::args().collect();
+    let scan = match scan_type {
+        ScanType::Sctp => "sctpinit".to_string(),
+        ScanType::IpProto => "ipproto".to_string(),
+        _ => scan_type.to_string(),
+    };
+
+    let mut xml = String::new();
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(xml, "<!DOCTYPE nmaprun>")?;
+    writeln!(
+        xml,
+        r#"<nmaprun scanner="tsunami" args="{}" start="{start}" startstr="{}" version="{}" xmloutputversion="1.05">"#,
+        escape(&args.join(" ")),
+        timestr(start),
+        env!("CARGO_PKG_VERSION"),
+    )?;
+    writeln!(
+        xml,
+        r#"<scaninfo type="{scan}" protocol="{protocol}" numservices="{}" services="{}"/>"#,
+        ports.len(),
+        port_ranges(&ports),
+    )?;
+
+    for (host, ports) in &per_host {
+        let mac = mac_addrs.get(host);
+        let reason = hosts
+            .up
+            .get(host)
+            .map_or("unknown-response".to_string(), |reason| reason.to_string());
+        let addrtype = match host {
+            IpAddr::V4(_) => "ipv4",
+            IpAddr::V6(_) => "ipv6",
+        };
+
+        writeln!(xml, r#"<host starttime="{start}" endtime="{end}">"#)?;
+        writeln!(
+            xml,
+            r#"<status state="up" reason="{reason}" reason_ttl="0"/>"#
+        )?;
+        writeln!(xml, r#"<address addr="{host}" addrtype="{addrtype}"/>"#)?;
+        if let Some(mac) = mac {
+            let (vendor, mac) = (vendor(*mac), mac.to_string().to_uppercase());
+            match vendor {
+                Some(vendor) => writeln!(
+                    xml,
+                    r#"<address addr="{mac}" addrtype="mac" vendor="{}"/>"#,
+                    escape(vendor)
+                )?,
+                None => writeln!(xml, r#"<address addr="{mac}" addrtype="mac"/>"#)?,
+            }
+        }
+        writeln!(xml, "<hostnames>\n</hostnames>")?;
+        writeln!(xml, "<ports>")?;
+
+        /* the states summed up come along with why the ports are in them */
+        let (listed, summed_up) = sum_up(ports);
+        for (state, ports) in &summed_up {
+            writeln!(
+                xml,
+                r#"<extraports state="{state}" count="{}">"#,
+                ports.len()
+            )?;
+
+            let mut by_reason: BTreeMap<Option<Reason>, Vec<Port>> = BTreeMap::new();
+            for (port, info) in ports {
+                by_reason.entry(info.reason).or_default().push(*port);
+            }
+            for (reason, ports) in by_reason {
+                let reason = reason.map_or("unknown".to_string(), |reason| reason.to_string());
+                writeln!(
+                    xml,
+                    r#"<extrareasons reason="{reason}" count="{}" proto="{protocol}" ports="{}"/>"#,
+                    ports.len(),
+                    port_ranges(&ports),
+                )?;
+            }
+
+            writeln!(xml, "</extraports>")?;
+        }
+
+        for (port, info) in listed {
+            let reason = info
+                .reason
+                .map_or("unknown".to_string(), |reason| reason.to_string());
+            writeln!(xml, r#"<port protocol="{protocol}" portid="{port}">"#)?;
+            writeln!(
+                xml,
+                r#"<state state="{}" reason="{reason}" reason_ttl="{}"/>"#,
+                info.status,
+                info.response.ttl.unwrap_or(0),
+            )?;
+            writeln!(xml, "</port>")?;
+        }
+
+        writeln!(xml, "</ports>")?;
+        writeln!(xml, "</host>")?;
+    }
+
+    let up = per_host.len();
+    let total = hosts.total.max(up);
+    writeln!(xml, "<runstats>")?;
+    writeln!(
+        xml,
+        r#"<finished time="{end}" timestr="{}" elapsed="{elapsed:.2}" summary="tsunami done; {total} IP address{} ({up} host{} up) scanned in {elapsed:.2} seconds" exit="success"/>"#,
+        timestr(end),
+        if total == 1 { "" } else { "es" },
+        if up == 1 { "" } else { "s" },
+    )?;
+    writeln!(
+        xml,
+        r#"<hosts up="{up}" down="{}" total="{total}"/>"#,
+        total - up
+    )?;
+    writeln!(xml, "</runstats>")?;
+    writeln!(xml, "</nmaprun>")?;
+
+    out.write_all(xml.as_bytes())?;
+
+    Ok(())
+}
+
+/// Writes a line per host, the way `nmap -oG` does, give or take the
+/// fields tsunami has nothing to put in:
+/// "Host: 10.0.0.1 Ports: 22/open/tcp, 80/open/tcp Ignored State: closed (998)".
+fn write_grepable(scan_type: ScanType, status: &HashMap<Probe, PortInfo>) -> Result<()> {
+    let mut per_host: BTreeMap<IpAddr, BTreeMap<Port, &PortInfo>> = BTreeMap::new();
+    for ((host, port), info) in status {
+        per_host.entry(*host).or_default().insert(*port, info);
+    }
+
+    let protocol = scan_type.protocol_name();
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+
+    for (host, ports) in &per_host {
+        let (listed, summed_up) = sum_up(ports);
+
+        let mut line = format!("Host: {host}");
+        if !listed.is_empty() {
+            let ports = listed
+                .iter()
+                .map(|(port, info)| format!("{port}/{}/{protocol}", info.status))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(line, " Ports: {ports}")?;
+        }
+        for (state, ports) in &summed_up {
+            write!(line, " Ignored State: {state} ({})", ports.len())?;
+        }
+
+        writeln!(stdout, "{line}")?;
+    }
+
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Writes a row per (host, port) pair, ordered by host and port, under a
+/// header, with the same columns as the records of the JSON formats, in
+/// the same order, whatever the scan found. What isn't known is left empty.
+fn write_csv(
+    out: impl Write,
+    scan_type: ScanType,
+    status: &HashMap<Probe, PortInfo>,
+) -> Result<()> {
+    let mut csv = csv::Writer::from_writer(out);
+
+    /* serializing writes the header along with the first record only */
+    let records = port_records(scan_type, status);
+    if records.is_empty() {
+        csv.write_record(PortRecord::COLUMNS)?;
+    }
+    for record in &records {
+        csv.serialize(record)?;
+    }
+
+    csv.flush()?;
+
+    Ok(())
+}
+
+/// Writes a summary for each host, as text: its MAC address if it's known,
+/// and how many of its ports are in each state but open, which the open
+/// ones were printed as they turned up.
+fn write_text(
+    status: &HashMap<Probe, PortInfo>,
+    mac_addrs: &HashMap<IpAddr, MacAddr>,
+) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    /* group the results by host so that each one gets its own summary */
+    let mut per_host: BTreeMap<IpAddr, Vec<PortInfo>> = BTreeMap::new();
+    for ((host, _), info) in status {
+        per_host.entry(*host).or_default().push(*info);
+    }
+
+    for (host, infos) in per_host {
+        let closed_count = infos
+            .iter()
+            .filter(|info| info.status == PortStatus::Closed)
+            .count();
+
+        if let Some(mac) = mac_addrs.get(&host) {
+            match vendor(*mac) {
+                Some(vendor) => writeln!(stdout, "{host}: mac address: {mac} ({vendor})")?,
+                None if is_locally_administered(*mac) => {
+                    writeln!(stdout, "{host}: mac address: {mac} (locally administered)")?
+                }
+                None => writeln!(stdout, "{host}: mac address: {mac}")?,
+            }
+        }
+
+        writeln!(stdout, "{host}: ports closed: {closed_count}")?;
+
+        /* break the filtered ports down by why we think they're filtered */
+        let mut filtered_reasons: BTreeMap<Reason, usize> = BTreeMap::new();
+        for info in infos
+            .iter()
+            .filter(|info| info.status == PortStatus::Filtered)
+        {
+            if let Some(reason) = info.reason {
+                *filtered_reasons.entry(reason).or_default() += 1;
+            }
+        }
+
+        let filtered_count: usize = filtered_reasons.values().sum();
+
+        if filtered_reasons.is_empty() {
+            writeln!(stdout, "{host}: ports filtered: {filtered_count}")?;
+        } else {
+            let breakdown = filtered_reasons
+                .iter()
+                .map(|(reason, count)| format!("{reason}: {count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            writeln!(
+                stdout,
+                "{host}: ports filtered: {filtered_count} ({breakdown})"
+            )?;
+        }
+
+        /* these only come up with some of the scan types */
+        for extra in [PortStatus::OpenFiltered, PortStatus::Unfiltered] {
+            let count = infos.iter().filter(|info| info.status == extra).count();
+            if count > 0 {
+                writeln!(stdout, "{host}: ports {extra}: {count}")?;
+            }
+        }
+
+        let retried_more_than_once_count = infos.iter().filter(|info| info.retried > 1).count();
+
+        writeln!(
+            stdout,
+            "{host}: ports retried more than once: {retried_more_than_once_count}"
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Makes a record of each (host, port) pair, ordered by host and port.
+fn port_records(scan_type: ScanType, status: &HashMap<Probe, PortInfo>) -> Vec<PortRecord> {
+    let status: BTreeMap<_, _> = status.iter().collect();
+
+    status
+        .iter()
+        .map(|((host, port), info)| PortRecord {
+            host: *host,
+            port: *port,
+            protocol: scan_type.protocol_name(),
+            status: info.status.to_string(),
+            reason: info.reason.map(|reason| reason.to_string()),
+            retries: info.retried.saturating_sub(1),
+            /* to the microsecond, which is as precise as it gets anyway */
+            rtt_ms: info.rtt.map(|rtt| rtt.as_micros() as f64 / 1e3),
+            ttl: info.response.ttl,
+            window: info.response.window,
+        })
+        .collect()
+}
+
+/// Splits the ports of a host into the ones that get listed one by one,
+/// in order, and the ones that get summed up by state: the open ports are
+/// always listed, whereas a state with more than a few ports to it isn't.
+fn sum_up<'a>(
+    ports: &BTreeMap<Port, &'a PortInfo>,
+) -> (HostPorts<'a>, BTreeMap<String, HostPorts<'a>>) {
+    let mut by_status: BTreeMap<String, HostPorts> = BTreeMap::new();
+    for (port, info) in ports {
+        by_status
+            .entry(info.status.to_string())
+            .or_default()
+            .push((*port, *info));
+    }
+
+    let (summed_up, listed): (BTreeMap<_, _>, BTreeMap<_, _>) =
+        by_status.into_iter().partition(|(_, ports)| {
+            ports.len() > EXTRAPORTS_THRESHOLD && ports[0].1.status != PortStatus::Open
+        });
+
+    let mut listed: Vec<_> = listed.into_values().flatten().collect();
+    listed.sort_unstable_by_key(|(port, _)| *port);
+
+    (listed, summed_up)
+}
+
+/// Lists the ports (sorted) the way nmap does, with the runs of them
+/// collapsed into ranges: "22,80,8000-8080".
+fn port_ranges(ports: &[Port]) -> String {
+    let mut ranges: Vec<(Port, Port)> = vec![];
+    for port in ports {
+        match ranges.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(*port) => *end = *port,
+            _ => ranges.push((*port, *port)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{start}-{end}"),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Escapes the characters that can't appear as they are in an attribute value.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Formats a time the way nmap does (ctime, in local time): "Mon Oct 17 23:15:10 2026".
+fn timestr(secs: u64) -> String {
+    let time = secs as libc::time_t;
+    let mut buf = [0 as libc::c_char; 64];
+
+    /* localtime_r and strftime only write to what they're handed */
+    let len = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return secs.to_string();
+        }
+        libc::strftime(
+            buf.as_mut_ptr(),
+            buf.len(),
+            c"%a %b %e %H:%M:%S %Y".as_ptr(),
+            &tm,
+        )
+    };
+
+    match len {
+        0 => secs.to_string(),
+        _ => unsafe { CStr::from_ptr(buf.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        escape, port_ranges, sum_up, timestr, write_csv, write_json, write_xml, Hosts,
+        OutputFormat, PortRecord,
+    };
+    use crate::{scan::ScanType, HostReason, Port, PortInfo, PortStatus, Probe, Reason, Response};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        net::IpAddr,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn info(status: PortStatus, reason: Reason, retried: usize) -> PortInfo {
+        PortInfo {
+            status,
+            retried,
+            reason: Some(reason),
+            rtt: None,
+            response: Response::default(),
+        }
+    }
+
+    fn host(host: &str) -> IpAddr {
+        host.parse().unwrap()
+    }
+
+    /* an open and a closed port that answered, and one that didn't, on one
+     * host, and a host with enough closed ports for them to be summed up */
+    fn scan() -> HashMap<Probe, PortInfo> {
+        let mut status = HashMap::new();
+
+        let mut open = info(PortStatus::Open, Reason::SynAck, 1);
+        open.rtt = Some(Duration::from_micros(467));
+        open.response = Response {
+            ttl: Some(64),
+            window: Some(64240),
+        };
+        status.insert((host("192.0.2.1"), 22), open);
+
+        let mut closed = info(PortStatus::Closed, Reason::Reset, 2);
+        closed.response = Response {
+            ttl: Some(63),
+            window: Some(0),
+        };
+        status.insert((host("192.0.2.1"), 23), closed);
+
+        let filtered = info(PortStatus::Filtered, Reason::NoResponse, 3);
+        status.insert((host("192.0.2.1"), 25), filtered);
+
+        for port in 1000..1030 {
+            status.insert(
+                (host("2001:db8::1"), port),
+                info(PortStatus::Closed, Reason::Reset, 1),
+            );
+        }
+        status.insert(
+            (host("2001:db8::1"), 1030),
+            info(PortStatus::Closed, Reason::Icmpv6Unreachable { code: 4 }, 1),
+        );
+        status.insert(
+            (host("2001:db8::1"), 8080),
+            info(
+                PortStatus::Filtered,
+                Reason::Icmpv6Unreachable { code: 1 },
+                1,
+            ),
+        );
+
+        status
+    }
+
+    #[test]
+    fn port_ranges_collapse_runs() {
+        assert_eq!(port_ranges(&[]), "");
+        assert_eq!(port_ranges(&[22]), "22");
+        assert_eq!(
+            port_ranges(&[1, 22, 80, 81, 82, 443, 8000, 8001]),
+            "1,22,80-82,443,8000-8001"
+        );
+        /* no overflow past the last port */
+        assert_eq!(port_ranges(&[65534, 65535]), "65534-65535");
+        assert_eq!(port_ranges(&[0, 65535]), "0,65535");
+    }
+
+    #[test]
+    fn escape_attribute_values() {
+        assert_eq!(escape("tsunami 10.0.0.1-50"), "tsunami 10.0.0.1-50");
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn sum_up_large_states() {
+        let closed = info(PortStatus::Closed, Reason::Reset, 1);
+        let open = info(PortStatus::Open, Reason::SynAck, 1);
+        let filtered = info(PortStatus::Filtered, Reason::NoResponse, 2);
+
+        let mut ports: BTreeMap<Port, &PortInfo> = BTreeMap::new();
+        for port in 1..=26 {
+            ports.insert(port, &closed);
+        }
+        for port in 100..=130 {
+            ports.insert(port, &open);
+        }
+        for port in [50, 20000] {
+            ports.insert(port, &filtered);
+        }
+
+        let (listed, summed_up) = sum_up(&ports);
+
+        /* open ports are listed however many there are */
+        let listed: Vec<Port> = listed.iter().map(|(port, _)| *port).collect();
+        let expected: Vec<Port> = [50].into_iter().chain(100..=130).chain([20000]).collect();
+        assert_eq!(listed, expected);
+
+        assert_eq!(summed_up.keys().collect::<Vec<_>>(), ["closed"]);
+        let closed: Vec<Port> = summed_up["closed"].iter().map(|(port, _)| *port).collect();
+        assert_eq!(closed, (1..=26).collect::<Vec<Port>>());
+
+        /* up to the threshold, a state is still listed */
+        ports.remove(&26);
+        let (listed, summed_up) = sum_up(&ports);
+        assert_eq!(listed.len(), 25 + 31 + 2);
+        assert!(summed_up.is_empty());
+    }
+
+    /* the header is written off the fields when there's a record to
+     * serialize, and COLUMNS has to say the same when there isn't */
+    #[test]
+    fn csv_header_matches_the_fields() {
+        let mut empty = vec![];
+        write_csv(&mut empty, ScanType::Syn, &HashMap::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(empty).unwrap(),
+            PortRecord::COLUMNS.join(",") + "\n"
+        );
+
+        let mut csv = vec![];
+        write_csv(&mut csv, ScanType::Syn, &scan()).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(
+            csv.lines().next(),
+            Some(PortRecord::COLUMNS.join(",").as_str())
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let mut status = scan();
+        status.retain(|(host, _), _| host.is_ipv4());
+
+        let mut csv = vec![];
+        write_csv(&mut csv, ScanType::Syn, &status).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "\
+host,port,protocol,status,reason,retries,rtt_ms,ttl,window
+192.0.2.1,22,tcp,open,syn-ack,0,0.467,64,64240
+192.0.2.1,23,tcp,closed,reset,1,,63,0
+192.0.2.1,25,tcp,filtered,no-response,2,,,
+"
+        );
+    }
+
+    #[test]
+    fn ndjson() {
+        let mut status = scan();
+        status.retain(|(_, port), _| *port < 1000 || *port == 8080);
+
+        let mut ndjson = vec![];
+        write_json(
+            &mut ndjson,
+            OutputFormat::Ndjson,
+            ScanType::Syn,
+            &status,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(ndjson).unwrap(),
+            r#"{"type":"port","host":"192.0.2.1","port":22,"protocol":"tcp","status":"open","reason":"syn-ack","retries":0,"rtt_ms":0.467,"ttl":64,"window":64240}
+{"type":"port","host":"192.0.2.1","port":23,"protocol":"tcp","status":"closed","reason":"reset","retries":1,"rtt_ms":null,"ttl":63,"window":0}
+{"type":"port","host":"192.0.2.1","port":25,"protocol":"tcp","status":"filtered","reason":"no-response","retries":2,"rtt_ms":null,"ttl":null,"window":null}
+{"type":"port","host":"2001:db8::1","port":8080,"protocol":"tcp","status":"filtered","reason":"admin-prohibited","retries":0,"rtt_ms":null,"ttl":null,"window":null}
+{"type":"summary","scan_type":"syn","hosts":[{"host":"192.0.2.1","mac":null,"vendor":null,"open":1,"closed":1,"filtered":1,"unfiltered":0,"open_filtered":0,"retried":2},{"host":"2001:db8::1","mac":null,"vendor":null,"open":0,"closed":0,"filtered":1,"unfiltered":0,"open_filtered":0,"retried":0}],"open":1,"closed":1,"filtered":2,"unfiltered":0,"open_filtered":0,"retried":2}
+"#
+        );
+    }
+
+    /* the single document holds the same records and summary */
+    #[test]
+    fn json() {
+        let status = scan();
+        let mac_addrs = HashMap::new();
+
+        let mut ndjson = vec![];
+        write_json(
+            &mut ndjson,
+            OutputFormat::Ndjson,
+            ScanType::Syn,
+            &status,
+            &mac_addrs,
+        )
+        .unwrap();
+        let mut json = vec![];
+        write_json(
+            &mut json,
+            OutputFormat::Json,
+            ScanType::Syn,
+            &status,
+            &mac_addrs,
+        )
+        .unwrap();
+
+        let mut lines: Vec<serde_json::Value> = ndjson
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        for line in &mut lines {
+            line.as_object_mut().unwrap().remove("type");
+        }
+        let summary = lines.pop().unwrap();
+
+        let document: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(document["ports"], serde_json::Value::Array(lines));
+        assert_eq!(document["summary"], summary);
+    }
+
+    #[test]
+    fn xml() {
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let finished = started + Duration::from_millis(1500);
+        let hosts = Hosts {
+            total: 3,
+            up: HashMap::from([(host("192.0.2.1"), HostReason::ArpResponse)]),
+        };
+
+        let mut xml = vec![];
+        write_xml(
+            &mut xml,
+            ScanType::Syn,
+            started,
+            finished,
+            &hosts,
+            &scan(),
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        let args = escape(&std::env::args().collect::<Vec<_>>().join(" "));
+        let (start, end) = (timestr(1_700_000_000), timestr(1_700_000_001));
+        let expected = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE nmaprun>
+<nmaprun scanner="tsunami" args="{args}" start="1700000000" startstr="{start}" version="{}" xmloutputversion="1.05">
+<scaninfo type="syn" protocol="tcp" numservices="35" services="22-23,25,1000-1030,8080"/>
+<host starttime="1700000000" endtime="1700000001">
+<status state="up" reason="arp-response" reason_ttl="0"/>
+<address addr="192.0.2.1" addrtype="ipv4"/>
+<hostnames>
+</hostnames>
+<ports>
+<port protocol="tcp" portid="22">
+<state state="open" reason="syn-ack" reason_ttl="64"/>
+</port>
+<port protocol="tcp" portid="23">
+<state state="closed" reason="reset" reason_ttl="63"/>
+</port>
+<port protocol="tcp" portid="25">
+<state state="filtered" reason="no-response" reason_ttl="0"/>
+</port>
+</ports>
+</host>
+<host starttime="1700000000" endtime="1700000001">
+<status state="up" reason="unknown-response" reason_ttl="0"/>
+<address addr="2001:db8::1" addrtype="ipv6"/>
+<hostnames>
+</hostnames>
+<ports>
+<extraports state="closed" count="31">
+<extrareasons reason="reset" count="30" proto="tcp" ports="1000-1029"/>
+<extrareasons reason="port-unreach" count="1" proto="tcp" ports="1030"/>
+</extraports>
+<port protocol="tcp" portid="8080">
+<state state="filtered" reason="admin-prohibited" reason_ttl="0"/>
+</port>
+</ports>
+</host>
+<runstats>
+<finished time="1700000001" timestr="{end}" elapsed="1.50" summary="tsunami done; 3 IP addresses (2 hosts up) scanned in 1.50 seconds" exit="success"/>
+<hosts up="2" down="1" total="3"/>
+</runstats>
+</nmaprun>
+"#,
+            env!("CARGO_PKG_VERSION"),
+        );
+
+        assert_eq!(String::from_utf8(xml).unwrap(), expected);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::congestion::{Aimd, Feedback};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How much a bucket holds by default, in seconds' worth of its rate. Large
+/// enough for the timers to keep up, small enough not to be noticed as a burst.
+const DEFAULT_BURST_SECS: f64 = 0.01;
+
+/// Below this, a wait is spent yielding rather than sleeping, since the
+/// timers don't go any finer and would quietly cap the rate.
+const TIMER_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// The rate the adaptive mode never goes over when there's no --rate given.
+const DEFAULT_ADAPTIVE_RATE: f64 = 10_000.0;
+
+/// Spaces out the probes. Given a rate in packets per second and/or a
+/// bandwidth in bits per second, it's a token bucket for each, and every
+/// probe waits until both can afford it. Otherwise, `batch_size` probes go
+/// out back to back, followed by a nap of `nap_after_batch` milliseconds.
+/// In the adaptive mode, the rate follows the loss observed instead.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    batch_size: usize,
+    nap_after_batch: f64,
+    burst: Option<u32>,
+    adaptive: Option<Aimd>,
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    sent: usize,
+}
+
+impl Pacer {
+    /// `burst` is how many packets may go out back to back at `rate`, by
+    /// default 10ms worth. The bandwidth bucket always holds 10ms worth,
+    /// but at least a full-sized packet. Given `feedback` from the receiver,
+    /// the rate adapts to the loss, without ever going over `rate`.
+    pub fn new(
+        batch_size: usize,
+        nap_after_batch: f64,
+        rate: Option<f64>,
+        burst: Option<u32>,
+        max_bandwidth: Option<f64>,
+        feedback: Option<Arc<Feedback>>,
+    ) -> Self {
+        let adaptive =
+            feedback.map(|feedback| Aimd::new(feedback, rate.unwrap_or(DEFAULT_ADAPTIVE_RATE)));
+        let packets = match &adaptive {
+            Some(aimd) => Some(aimd.rate()),
+            None => rate,
+        }
+        .map(|rate| TokenBucket::new(rate, capacity(rate, burst)));
+        let bytes = max_bandwidth.map(|bits_per_sec| {
+            let rate = bits_per_sec / 8.0;
+            TokenBucket::new(rate, (rate * DEFAULT_BURST_SECS).max(1500.0))
+        });
+
+        Self {
+            batch_size: batch_size.max(1),
+            nap_after_batch,
+            burst,
+            adaptive,
+            packets,
+            bytes,
+            sent: 0,
+        }
+    }
+
+    /// Accounts for a sent packet of `len` bytes, and waits until
+    /// the next one is allowed to go out.
+    pub async fn tick(&mut self, len: usize) {
+        let wait = self.wait(len);
+
+        if wait >= TIMER_GRANULARITY {
+            tokio::time::sleep(wait).await;
+        } else if !wait.is_zero() {
+            let deadline = Instant::now() + wait;
+            while Instant::now() < deadline {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    /// Like `tick`, but for the threads that can't await.
+    pub fn tick_blocking(&mut self, len: usize) {
+        let wait = self.wait(len);
+
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    fn wait(&mut self, len: usize) -> Duration {
+        self.sent += 1;
+
+        if self.packets.is_none() && self.bytes.is_none() {
+            return match self.sent % self.batch_size {
+                0 => Duration::from_secs_f64(self.nap_after_batch / 1000.0),
+                _ => Duration::ZERO,
+            };
+        }
+
+        if let (Some(aimd), Some(bucket)) = (&mut self.adaptive, &mut self.packets) {
+            if let Some(rate) = aimd.poll() {
+                bucket.set_rate(rate, capacity(rate, self.burst));
+            }
+        }
+
+        let packets_wait = self.packets.as_mut().map(|bucket| bucket.take(1.0));
+        let bytes_wait = self.bytes.as_mut().map(|bucket| bucket.take(len as f64));
+
+        packets_wait
+            .into_iter()
+            .chain(bytes_wait)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// How many packets may go out back to back at `rate`.
+fn capacity(rate: f64, burst: Option<u32>) -> f64 {
+    match burst {
+        Some(burst) => burst as f64,
+        None => rate * DEFAULT_BURST_SECS,
+    }
+    .max(1.0)
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Changes the rate from now on, leaving the tokens already in alone.
+    fn set_rate(&mut self, rate: f64, capacity: f64) {
+        self.take(0.0);
+        self.rate = rate;
+        self.capacity = capacity;
+        self.tokens = self.tokens.min(capacity);
+    }
+
+    /// Takes out `cost` tokens, going into debt if there aren't enough,
+    /// and returns how long it takes to pay the debt off. Refilling by the
+    /// time that actually went by makes up for oversleeping.
+    fn take(&mut self, cost: f64) -> Duration {
+        self.take_at(cost, Instant::now())
+    }
+
+    fn take_at(&mut self, cost: f64, now: Instant) -> Duration {
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate;
+
+        self.tokens = (self.tokens + refill).min(self.capacity) - cost;
+        self.last_refill = now;
+
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Pacer, TokenBucket};
+    use std::time::Duration;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(100.0, 2.0);
+        let start = bucket.last_refill;
+
+        /* a full bucket lets a burst of its capacity through */
+        assert_eq!(bucket.take_at(1.0, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(1.0, start), Duration::ZERO);
+
+        /* then each packet waits for its token, 10ms at 100/s */
+        assert_eq!(bucket.take_at(1.0, start), 10 * MS);
+        assert_eq!(bucket.take_at(1.0, start + 10 * MS), 10 * MS);
+        assert_eq!(bucket.take_at(1.0, start + 30 * MS), Duration::ZERO);
+
+        /* idling only refills up to the capacity */
+        assert_eq!(bucket.take_at(1.0, start + 1000 * MS), Duration::ZERO);
+        assert_eq!(bucket.take_at(1.0, start + 1000 * MS), Duration::ZERO);
+        assert_eq!(bucket.take_at(1.0, start + 1000 * MS), 10 * MS);
+    }
+
+    #[test]
+    fn token_bucket_bytes() {
+        let mut bucket = TokenBucket::new(1000.0, 1500.0);
+        let start = bucket.last_refill;
+
+        assert_eq!(bucket.take_at(1500.0, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(500.0, start), 500 * MS);
+    }
+
+    #[test]
+    fn batches() {
+        let mut pacer = Pacer::new(3, 5.0, None, None, None, None);
+
+        let waits: Vec<Duration> = (0..6).map(|_| pacer.wait(60)).collect();
+        assert_eq!(waits, [0, 0, 5, 0, 0, 5].map(|n| n * MS));
+    }
+}
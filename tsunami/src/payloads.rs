@@ -0,0 +1,66 @@
+use crate::Port;
+
+/* A standard query for the NS records of the root zone. */
+const DNS: &[u8] = &[
+    0x13, 0x37, /* transaction id */
+    0x01, 0x00, /* flags: recursion desired */
+    0x00, 0x01, /* one question */
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, /* no answers, authorities or additionals */
+    0x00, /* the root name */
+    0x00, 0x02, /* type NS */
+    0x00, 0x01, /* class IN */
+];
+
+/* An NTPv4 client request, everything but LI/VN/mode left zeroed. */
+const NTP: &[u8] = &[
+    0xe3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/* A NetBIOS node status request for the wildcard name "*". */
+const NETBIOS_NS: &[u8] = &[
+    0x13, 0x37, /* transaction id */
+    0x00, 0x00, /* flags */
+    0x00, 0x01, /* one question */
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, /* no answers, authorities or additionals */
+    0x20, b'C', b'K', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A',
+    b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A',
+    b'A', 0x00, /* the encoded name */
+    0x00, 0x21, /* type NBSTAT */
+    0x00, 0x01, /* class IN */
+];
+
+/* An SNMPv1 get-request for sysDescr.0 in the "public" community. */
+const SNMP: &[u8] = &[
+    0x30, 0x26, /* message */
+    0x02, 0x01, 0x00, /* version 1 */
+    0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', /* community */
+    0xa0, 0x19, /* get-request */
+    0x02, 0x01, 0x01, /* request id */
+    0x02, 0x01, 0x00, /* error status */
+    0x02, 0x01, 0x00, /* error index */
+    0x30, 0x0e, 0x30, 0x0c, /* varbind list with a single varbind */
+    0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00, /* 1.3.6.1.2.1.1.1.0 */
+    0x05, 0x00, /* null value */
+];
+
+const SSDP: &[u8] = b"M-SEARCH * HTTP/1.1\r\n\
+HOST: 239.255.255.250:1900\r\n\
+MAN: \"ssdp:discover\"\r\n\
+MX: 1\r\n\
+ST: ssdp:all\r\n\r\n";
+
+/// Returns a payload that the service commonly found on the port is
+/// going to answer, since most UDP services ignore empty datagrams.
+/// Ports we don't know anything about get an empty datagram.
+pub fn udp_payload(port: Port) -> &'static [u8] {
+    match port {
+        53 | 5353 => DNS,
+        123 => NTP,
+        137 => NETBIOS_NS,
+        161 => SNMP,
+        1900 => SSDP,
+        _ => &[],
+    }
+}
@@ -0,0 +1,112 @@
+const ROUNDS: u64 = 4;
+
+/// A keyed pseudo-random permutation of `0..range`, for walking the probes
+/// in an order that spreads them across hosts and ports, without having to
+/// shuffle a copy of them. It's the generalized Feistel cipher of Black and
+/// Rogaway that masscan calls blackrock: the indices are split into a × b
+/// pairs for the Feistel rounds, and the ones that land past the end of the
+/// range get encrypted again until they don't ("cycle walking"). The same
+/// seed always gives the same order, so that a scan can be reproduced.
+pub struct Permutation {
+    range: u64,
+    a: u64,
+    b: u64,
+    seed: u64,
+}
+
+impl Permutation {
+    pub fn new(range: u64, seed: u64) -> Self {
+        /* a × b has to cover the range, and a shouldn't be much smaller than b,
+         * so that the walks past the end stay short */
+        let split = range.isqrt();
+        let a = split.saturating_sub(1).max(1);
+        let mut b = split + 1;
+        while a * b < range {
+            b += 1;
+        }
+
+        Self { range, a, b, seed }
+    }
+
+    /// Maps an index in `0..range` to where it's permuted to.
+    pub fn get(&self, index: u64) -> u64 {
+        let mut permuted = self.encrypt(index);
+        while permuted >= self.range {
+            permuted = self.encrypt(permuted);
+        }
+        permuted
+    }
+
+    /// Iterates over the permuted indices.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.range).map(|index| self.get(index))
+    }
+
+    fn encrypt(&self, m: u64) -> u64 {
+        let (mut left, mut right) = (m % self.a, m / self.a);
+
+        for round in 1..=ROUNDS {
+            let modulus = match round & 1 {
+                1 => self.a,
+                _ => self.b,
+            };
+            let mixed = (left + self.round(round, right) % modulus) % modulus;
+
+            left = right;
+            right = mixed;
+        }
+
+        match ROUNDS & 1 {
+            1 => self.a * left + right,
+            _ => self.a * right + left,
+        }
+    }
+
+    /// The round function, keyed by the seed. It's spelled out rather than
+    /// left to a hasher from std, whose algorithm may change from one Rust
+    /// release to the next, and with it the order a seed gives.
+    fn round(&self, round: u64, right: u64) -> u64 {
+        splitmix64(splitmix64(self.seed ^ round.wrapping_mul(GOLDEN_GAMMA)) ^ right)
+    }
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The output function of SplitMix64 (Steele, Lea and Flood), which mixes
+/// the bits of a 64-bit value thoroughly.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(GOLDEN_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Permutation;
+
+    #[test]
+    fn bijection() {
+        for range in [1, 2, 3, 5, 7, 10, 97, 100, 255, 1000, 4099] {
+            for seed in [0, 1, 0xdead_beef, u64::MAX] {
+                let mut seen = vec![false; range as usize];
+                for index in Permutation::new(range, seed).iter() {
+                    assert!(index < range);
+                    assert!(!seen[index as usize], "{index} came up twice");
+                    seen[index as usize] = true;
+                }
+                assert!(seen.iter().all(|seen| *seen));
+            }
+        }
+    }
+
+    /* pinned, so that a change to the round function doesn't go unnoticed */
+    #[test]
+    fn reproducible() {
+        let order: Vec<u64> = Permutation::new(10, 42).iter().collect();
+        assert_eq!(order, [1, 7, 9, 3, 5, 4, 0, 6, 8, 2]);
+
+        let other: Vec<u64> = Permutation::new(10, 43).iter().collect();
+        assert_ne!(order, other);
+    }
+}
@@ -1,157 +1,964 @@
 use crate::{
-    net::{create_recv_sock, IP_HDR_LEN},
-    Message, Port, PortInfo, PortStatus,
+    congestion::{Feedback, RttEstimator},
+    net::{
+        create_recv_sock, ipv4_payload, recv_on, sequence_number, SourcePorts, IPV6_HDR_LEN,
+        IP_HDR_LEN, MAX_PACKET_LEN, SCTP_HDR_LEN, SOURCE_PORT,
+    },
+    output::Output,
+    scan::ScanType,
+    Message, Port, PortInfo, PortStatus, Probe, Reason, Response, Stamp, Verdict,
+};
+use anyhow::Result;
+use pnet::{
+    packet::{
+        icmp::{destination_unreachable::DestinationUnreachablePacket, IcmpTypes},
+        icmpv6::{Icmpv6Packet, Icmpv6Types},
+        ip::IpNextHeaderProtocols,
+        ipv4::Ipv4Packet,
+        ipv6::Ipv6Packet,
+        tcp::{TcpFlags, TcpPacket},
+        udp::UdpPacket,
+        Packet,
+    },
+    util::MacAddr,
+};
+use raw_socket::{
+    tokio::prelude::{CMsg, Level, Name, RawSocket},
+    Domain, Protocol,
 };
-use anyhow::{bail, Result};
-use pnet::packet::tcp::{TcpFlags, TcpPacket};
 use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BinaryHeap, HashMap},
+    io::IoSliceMut,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::{
+    sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender},
+    task::unconstrained,
+    time::sleep_until,
 };
-use tokio::{sync::mpsc::Sender, time::timeout};
-use tracing::{debug, error, info, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 
-const SYNACK: u8 = TcpFlags::SYN | TcpFlags::ACK;
-const RSTACK: u8 = TcpFlags::RST | TcpFlags::ACK;
+/// Something for the receiver to look at: a packet that came in on one
+/// of the sockets, a verdict reached by a connect() worker, word from the
+/// main thread that a probe went out, or a probe timing out.
+enum Event {
+    Ipv4(usize),
+    Ipv6(usize, SocketAddr, Option<u8>),
+    Icmp(usize),
+    Icmp6(usize, Option<u8>),
+    Verdict(Verdict),
+    Sent(Stamp),
+    Expired,
+}
 
 #[instrument(skip_all, name = "receiver")]
+#[allow(clippy::too_many_arguments)]
 pub async fn receive(
-    combined: HashSet<Port>,
-    tx: Sender<Message>,
+    combined: Vec<Probe>,
+    src_ip_addrs: HashMap<IpAddr, IpAddr>,
+    mac_addrs: HashMap<IpAddr, MacAddr>,
+    key: RandomState,
+    scan_type: ScanType,
+    tx: UnboundedSender<Message>,
+    mut sent: UnboundedReceiver<Stamp>,
+    mut verdicts: Option<Receiver<Verdict>>,
     max_retries: usize,
+    mut rtt: RttEstimator,
+    feedback: Arc<Feedback>,
+    source_ports: SourcePorts,
+    interface: Option<String>,
+    mut output: Box<dyn Output>,
 ) -> Result<()> {
-    let sock = create_recv_sock()?;
-    let mut buf = [0u8; 576];
+    /* only open the sockets for the address families we're going to see,
+     * listening for the transport protocol the probes are sent over;
+     * connect scans don't get any, the workers report to us instead,
+     * and IP protocol scans only ever hear back over ICMP */
+    let raw = scan_type.needs_raw_sockets();
+    let protocol = Protocol::from(scan_type.protocol().0 as i32);
+    let interface = interface.as_deref();
+    let (sock, icmp_sock) = match raw && combined.iter().any(|(host, _)| host.is_ipv4()) {
+        true if scan_type == ScanType::IpProto => (
+            None,
+            Some(create_recv_sock(
+                Domain::ipv4(),
+                Protocol::icmpv4(),
+                interface,
+            )?),
+        ),
+        true => (
+            Some(create_recv_sock(Domain::ipv4(), protocol, interface)?),
+            Some(create_recv_sock(
+                Domain::ipv4(),
+                Protocol::icmpv4(),
+                interface,
+            )?),
+        ),
+        false => (None, None),
+    };
+    let (sock6, icmp_sock6) = match raw && combined.iter().any(|(host, _)| host.is_ipv6()) {
+        true => {
+            /* raw IPv6 sockets don't hand us the header, so the hop limit
+             * of the replies has to be asked for separately */
+            let sock6 = create_recv_sock(Domain::ipv6(), protocol, interface)?;
+            sock6.set_sockopt(Level::IPV6, Name::IPV6_RECVHOPLIMIT, &1i32)?;
+            let icmp_sock6 = create_recv_sock(Domain::ipv6(), Protocol::icmpv6(), interface)?;
+            icmp_sock6.set_sockopt(Level::IPV6, Name::IPV6_RECVHOPLIMIT, &1i32)?;
+            (Some(sock6), Some(icmp_sock6))
+        }
+        false => (None, None),
+    };
+    let mut buf = vec![0u8; MAX_PACKET_LEN];
+    let mut buf6 = vec![0u8; MAX_PACKET_LEN];
+    let mut ctrl6 = [0u8; 64];
+    let mut icmp_buf = vec![0u8; MAX_PACKET_LEN];
+    let mut icmp_buf6 = vec![0u8; MAX_PACKET_LEN];
+    let mut icmp_ctrl6 = [0u8; 64];
 
     /* the main HashMap to keep track of the probes */
     let mut status = combined
         .iter()
-        .map(|port| {
+        .map(|probe| {
             (
-                *port,
+                *probe,
                 PortInfo {
                     status: PortStatus::NotInspected,
                     retried: 0,
+                    reason: None,
+                    rtt: None,
+                    response: Response::default(),
                 },
             )
         })
-        .collect::<HashMap<u16, PortInfo>>();
+        .collect::<HashMap<Probe, PortInfo>>();
+
+    /* when the probes that are still waiting for a reply went out, and
+     * when each of them times out, soonest first */
+    let mut sent_at: HashMap<Probe, Instant> = HashMap::new();
+    let mut deadlines: BinaryHeap<Reverse<(Instant, Probe)>> = BinaryHeap::new();
+
+    /* the probes that have neither been answered nor given up on yet */
+    let mut pending = status.len();
 
     /* trigger the machinery */
-    tx.send(Message::Payload(status.keys().copied().collect()))
-        .await?;
+    tx.send(Message::Payload(combined))?;
     info!("triggered the machinery");
 
     /* increase the 'retried' field of all ports we just dispatched */
     status.iter_mut().for_each(|(_, info)| info.retried += 1);
 
     loop {
-        let (_bytes_recvd, _ip_addr) =
-            match timeout(Duration::from_millis(300), sock.recv_from(&mut buf)).await {
-                Ok(result) => {
-                    let (bytes, ip) = result.unwrap();
+        /* if we inspected everything, break the main thread */
+        if pending == 0 {
+            info!("all done, sending Message::Break");
+            tx.send(Message::Break)?;
+            break;
+        }
+
+        let next_deadline = deadlines.peek().map(|Reverse((deadline, _))| *deadline);
+
+        /* The word that a probe went out comes first, so that a reply that's
+         * quick to come back doesn't beat it to the receiver. The sockets
+         * don't count against tokio's budget the way the channel does, so
+         * the channel is exempted from it, or it'd still fall behind. */
+        let event = tokio::select! {
+            biased;
+            Some(stamp) = unconstrained(sent.recv()) => Ok(Event::Sent(stamp)),
+            r = recv_on(sock.as_ref(), &mut buf) => r.map(|(n, _)| Event::Ipv4(n)),
+            r = recv_with_hop_limit(sock6.as_ref(), &mut buf6, &mut ctrl6) => {
+                r.map(|(n, addr, hop_limit)| Event::Ipv6(n, addr, hop_limit))
+            }
+            r = recv_on(icmp_sock.as_ref(), &mut icmp_buf) => r.map(|(n, _)| Event::Icmp(n)),
+            r = recv_with_hop_limit(icmp_sock6.as_ref(), &mut icmp_buf6, &mut icmp_ctrl6) => {
+                r.map(|(n, _, hop_limit)| Event::Icmp6(n, hop_limit))
+            }
+            verdict = recv_verdict(verdicts.as_mut()) => Ok(Event::Verdict(verdict)),
+            _ = sleep_until_deadline(next_deadline) => Ok(Event::Expired),
+        }?;
 
-                    (Some(bytes), Some(ip))
+        /* Raw IPv4 sockets hand us the IP header + the transport header
+         * which starts immediately after the IP header, whereas raw
+         * IPv6 sockets strip the IP header and hand us the rest only. */
+        let (host, payload, ttl) = match event {
+            Event::Ipv4(bytes_recvd) => {
+                match ipv4_payload(&buf[..bytes_recvd], scan_type.protocol()) {
+                    Some((src, payload)) => (IpAddr::V4(src), payload, ttl(&buf[..bytes_recvd])),
+                    None => {
+                        debug!("couldn't parse the ipv4 packet, ignoring");
+                        continue;
+                    }
                 }
-                Err(_) => {
-                    info!("timed out after 300ms");
-
-                    /* we haven't received anything for 300ms and it's a good
-                     * opportunity to check which ports have remained uninspected. */
-                    let not_inspected = status
-                        .iter()
-                        .filter(|(_, info)| {
-                            info.status == PortStatus::NotInspected && info.retried < max_retries
-                        })
-                        .map(|(port, _)| port.to_owned())
-                        .collect::<Vec<u16>>();
-
-                    /* if we inspected everything, break the main thread */
-                    if not_inspected.is_empty() {
-                        info!("all done, sending Message::Break");
-                        tx.send(Message::Break).await?;
+            }
+            Event::Ipv6(bytes_recvd, SocketAddr::V6(addr), hop_limit) => {
+                (IpAddr::V6(*addr.ip()), &buf6[..bytes_recvd], hop_limit)
+            }
+            Event::Ipv6(_, SocketAddr::V4(_), _) => continue,
+            Event::Icmp(bytes_recvd) => {
+                /* ICMP errors don't tell us anything on their own, but they quote
+                 * the header of the probe that triggered them, which we can match
+                 * against our probes the same way we match the direct replies. */
+                let Some((probe, code)) = icmp_unreachable(
+                    &icmp_buf[..bytes_recvd],
+                    &src_ip_addrs,
+                    &key,
+                    source_ports,
+                    scan_type,
+                ) else {
+                    continue;
+                };
+
+                let response = Response {
+                    ttl: ttl(&icmp_buf[..bytes_recvd]),
+                    window: None,
+                };
+                record_icmp_error(
+                    &mut status,
+                    &mut sent_at,
+                    &mut pending,
+                    &mut rtt,
+                    &feedback,
+                    probe,
+                    scan_type.classify_icmp(code),
+                    response,
+                    output.as_mut(),
+                )?;
+
+                continue;
+            }
+            Event::Icmp6(bytes_recvd, hop_limit) => {
+                let Some((probe, code)) = icmpv6_unreachable(
+                    &icmp_buf6[..bytes_recvd],
+                    &src_ip_addrs,
+                    &key,
+                    source_ports,
+                    scan_type,
+                ) else {
+                    continue;
+                };
+
+                let response = Response {
+                    ttl: hop_limit,
+                    window: None,
+                };
+                record_icmp_error(
+                    &mut status,
+                    &mut sent_at,
+                    &mut pending,
+                    &mut rtt,
+                    &feedback,
+                    probe,
+                    scan_type.classify_icmpv6(code),
+                    response,
+                    output.as_mut(),
+                )?;
+
+                continue;
+            }
+            Event::Verdict(verdict) => {
+                record(
+                    &mut status,
+                    &mut sent_at,
+                    &mut pending,
+                    &mut rtt,
+                    &feedback,
+                    verdict,
+                    Response::default(),
+                    output.as_mut(),
+                )?;
+                continue;
+            }
+            Event::Sent((probe, at)) => {
+                /* connect scans only count a probe as sent once the attempt is
+                 * over, by which time the verdict may well be in already */
+                if let Some(info) = status.get(&probe) {
+                    if info.status == PortStatus::NotInspected {
+                        if info.retried == 1 {
+                            feedback.sent();
+                        }
+
+                        sent_at.insert(probe, at);
+                        deadlines.push(Reverse((at + rtt.timeout(info.retried), probe)));
+                    }
+                }
+                continue;
+            }
+            Event::Expired => {
+                let now = Instant::now();
+                let mut timed_out = vec![];
+
+                while let Some(Reverse((deadline, probe))) = deadlines.peek().copied() {
+                    if deadline > now {
                         break;
-                    } else {
-                        /* otherwise, dispatch the ones that have remained uninspected,
-                         * but if and only if they haven't been retried past the 'max_retries'
-                         * upper bound. */
-                        status
-                            .iter_mut()
-                            .filter(|(port, _)| not_inspected.contains(port))
-                            .for_each(|(_, info)| {
-                                if info.retried < max_retries {
-                                    info.retried += 1
-                                }
-                            });
-
-                        info!(
-                            "dispatching another batch of size {} to the main thread",
-                            not_inspected.len()
-                        );
-                        tx.send(Message::Payload(not_inspected)).await?;
                     }
+                    deadlines.pop();
+
+                    /* the probe may have been answered in the meantime */
+                    let Some(info) = status.get_mut(&probe) else {
+                        continue;
+                    };
+                    if info.status != PortStatus::NotInspected {
+                        continue;
+                    }
+
+                    /* send it again, unless it's been retried past the
+                     * 'max_retries' upper bound, in which case it's given up on */
+                    match info.retried < max_retries {
+                        true => {
+                            info.retried += 1;
+                            timed_out.push(probe);
+                        }
+                        false => {
+                            info.status = scan_type.no_response();
+                            info.reason = Some(Reason::NoResponse);
+                            sent_at.remove(&probe);
+                            pending -= 1;
+                        }
+                    }
+                }
 
-                    (None, None)
+                if !timed_out.is_empty() {
+                    info!(
+                        "{} probes timed out after {:?}, dispatching them again",
+                        timed_out.len(),
+                        rtt.timeout(1)
+                    );
+                    tx.send(Message::Payload(timed_out))?;
                 }
-            };
 
-        /* The buffer contains the IP header + the TCP segment
-         * which starts immediately after the IP header */
-        let tcp_packet = match TcpPacket::new(&buf[IP_HDR_LEN as usize..]) {
-            Some(packet) => packet,
-            None => {
-                error!("couldn't make tcp packet");
-                bail!("couldn't make tcp packet");
+                continue;
             }
         };
 
-        /* The 'source' field in the TCP packet is now what used to be
-         * the 'destination' field when we were sending out the probe. */
-        let port = tcp_packet.get_source();
-        debug!(port, "got port");
+        /* Make sure the reply comes from a scanned target. */
+        let Some(src_ip_addr) = src_ip_addrs.get(&host) else {
+            debug!(%host, "reply isn't from a scanned target, ignoring");
+            continue;
+        };
 
-        match tcp_packet.get_flags() {
-            SYNACK => {
-                if let Some(info) = status.get_mut(&port) {
-                    info.status = PortStatus::Open;
-                    println!("{port}: open");
-                }
+        let src_ip_addr = *src_ip_addr;
+        let verdict = match scan_type.protocol() {
+            IpNextHeaderProtocols::Udp => udp_reply(host, payload, src_ip_addr, &key, source_ports),
+            IpNextHeaderProtocols::Sctp => {
+                sctp_reply(host, payload, src_ip_addr, &key, source_ports)
             }
-            RSTACK => {
-                if let Some(info) = status.get_mut(&port) {
-                    info.status = PortStatus::Closed;
-                }
+            _ => tcp_reply(host, payload, src_ip_addr, &key, source_ports, scan_type),
+        };
+
+        if let Some(verdict) = verdict {
+            let window = match scan_type.protocol() {
+                IpNextHeaderProtocols::Tcp => TcpPacket::new(payload).map(|tcp| tcp.get_window()),
+                _ => None,
+            };
+            record(
+                &mut status,
+                &mut sent_at,
+                &mut pending,
+                &mut rtt,
+                &feedback,
+                verdict,
+                Response { ttl, window },
+                output.as_mut(),
+            )?;
+        }
+    }
+
+    output.finish(&status, &mac_addrs)?;
+
+    info!("exiting");
+
+    Ok(())
+}
+
+/// Records the status of a probe we got an answer to. The first answer is
+/// the one that times the round trip, and one that comes in after we gave up
+/// on the probe still beats silence. The open ports are told about as they
+/// turn up.
+#[allow(clippy::too_many_arguments)]
+fn record(
+    status: &mut HashMap<Probe, PortInfo>,
+    sent_at: &mut HashMap<Probe, Instant>,
+    pending: &mut usize,
+    rtt: &mut RttEstimator,
+    feedback: &Feedback,
+    ((host, port), port_status, reason): Verdict,
+    response: Response,
+    output: &mut dyn Output,
+) -> Result<()> {
+    let Some(info) = status.get_mut(&(host, port)) else {
+        return Ok(());
+    };
+
+    if info.status == PortStatus::NotInspected {
+        *pending -= 1;
+
+        /* timed from the last time the probe went out, which is
+         * only good enough for the estimate if it went out once */
+        info.rtt = sent_at.remove(&(host, port)).map(|at| at.elapsed());
+        if info.retried == 1 {
+            feedback.reply();
+
+            if let Some(rtt_sample) = info.rtt {
+                rtt.sample(rtt_sample);
+            }
+        }
+    }
+
+    /* a retransmitted probe may well draw a second reply */
+    let newly_open = port_status == PortStatus::Open && info.status != PortStatus::Open;
+
+    info.status = port_status;
+    info.reason = Some(reason);
+    info.response = response;
+
+    if newly_open {
+        output.open(host, port)?;
+    }
+
+    Ok(())
+}
+
+/// Records the status an ICMP error that quotes one of our probes implies,
+/// if it implies any. An ICMP error never overrides a direct reply.
+#[allow(clippy::too_many_arguments)]
+fn record_icmp_error(
+    status: &mut HashMap<Probe, PortInfo>,
+    sent_at: &mut HashMap<Probe, Instant>,
+    pending: &mut usize,
+    rtt: &mut RttEstimator,
+    feedback: &Feedback,
+    probe: Probe,
+    classified: Option<(PortStatus, Reason)>,
+    response: Response,
+    output: &mut dyn Output,
+) -> Result<()> {
+    let (Some(info), Some((port_status, reason))) = (status.get(&probe), classified) else {
+        return Ok(());
+    };
+
+    if info
+        .reason
+        .is_some_and(|reason| reason != Reason::NoResponse)
+    {
+        return Ok(());
+    }
+
+    record(
+        status,
+        sent_at,
+        pending,
+        rtt,
+        feedback,
+        (probe, port_status, reason),
+        response,
+        output,
+    )
+}
+
+/// Receives on an IPv6 socket if there is one, like `recv_on`, along with
+/// the hop limit the packet came in with.
+async fn recv_with_hop_limit(
+    sock: Option<&RawSocket>,
+    buf: &mut [u8],
+    ctrl: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<u8>)> {
+    let Some(sock) = sock else {
+        return std::future::pending().await;
+    };
+
+    /* a control message that isn't there reads as zeroes */
+    ctrl.fill(0);
+    let (n, addr) = sock.recv_msg(&[IoSliceMut::new(buf)], Some(ctrl)).await?;
+    let hop_limit = CMsg::decode(ctrl).find_map(|msg| match msg {
+        CMsg::Ipv6HopLimit(hop_limit) => u8::try_from(hop_limit).ok(),
+        _ => None,
+    });
+
+    Ok((n, addr, hop_limit))
+}
+
+/// The TTL of an IPv4 packet.
+fn ttl(packet: &[u8]) -> Option<u8> {
+    Ipv4Packet::new(packet).map(|ipv4_packet| ipv4_packet.get_ttl())
+}
+
+/// Sleeps until the deadline, if there's one, or forever otherwise.
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Checks that a TCP segment is a reply to one of our probes and maps it
+/// to the port status it implies.
+fn tcp_reply(
+    host: IpAddr,
+    segment: &[u8],
+    src_ip_addr: IpAddr,
+    key: &RandomState,
+    source_ports: SourcePorts,
+    scan_type: ScanType,
+) -> Option<Verdict> {
+    let Some(tcp_packet) = TcpPacket::new(segment) else {
+        debug!(%host, "segment is too short for a tcp header, ignoring");
+        return None;
+    };
+
+    /* The 'source' field in the TCP packet is now what used to be
+     * the 'destination' field when we were sending out the probe. */
+    let port = tcp_packet.get_source();
+    debug!(%host, port, "got port");
+
+    /* Make sure the segment is a reply to one of our probes: it has to
+     * be addressed to the source port of the probe to that port, and
+     * acknowledge the sequence number we derived for that 4-tuple.
+     * Segments without the ACK bit carry no acknowledgement number, so
+     * for those we check the sequence number instead, if the probe set
+     * the number the reply echoes (a bare RST from a middlebox, say,
+     * can't be checked either way). */
+    let src_port = source_ports.pick(key, src_ip_addr, host, port);
+    if tcp_packet.get_destination() != src_port {
+        debug!(%host, port, "segment isn't addressed to our source port, ignoring");
+        return None;
+    }
+
+    let flags = tcp_packet.get_flags();
+    let sequence = sequence_number(key, src_ip_addr, src_port, host, port);
+    let acknowledged = match (flags & TcpFlags::ACK, scan_type.expected_seq(sequence)) {
+        (0, Some(expected_seq)) => tcp_packet.get_sequence() == expected_seq,
+        (0, None) => true,
+        _ => tcp_packet.get_acknowledgement() == scan_type.expected_ack(sequence),
+    };
+
+    if !acknowledged {
+        debug!(%host, port, "segment doesn't acknowledge our probe, ignoring");
+        return None;
+    }
+
+    /* A bare SYN (a simultaneous open) acknowledges nothing and starts a
+     * sequence of its own, so there's nothing in it to check against the
+     * cookie, and anyone who knows our source port can send one. What we
+     * can rule out is our own probe looping back to the raw socket when the
+     * port scanned is our source port on our own address: it comes from
+     * there, and its sequence number is the cookie itself. */
+    if flags & (TcpFlags::SYN | TcpFlags::ACK | TcpFlags::RST) == TcpFlags::SYN
+        && (tcp_packet.get_sequence() == sequence || (host, port) == (src_ip_addr, src_port))
+    {
+        debug!(%host, port, "segment is our own probe, ignoring");
+        return None;
+    }
+
+    match scan_type.classify(flags, tcp_packet.get_window()) {
+        Some((port_status, reason)) => Some(((host, port), port_status, reason)),
+        None => {
+            warn!(%host, port, flags, "port wasn't expected");
+            None
+        }
+    }
+}
+
+/// Checks that a UDP datagram is a reply to one of our probes, in which
+/// case the port is open. There's no sequence number to check here, so
+/// the ports are all we've got.
+fn udp_reply(
+    host: IpAddr,
+    datagram: &[u8],
+    src_ip_addr: IpAddr,
+    key: &RandomState,
+    source_ports: SourcePorts,
+) -> Option<Verdict> {
+    let Some(udp_packet) = UdpPacket::new(datagram) else {
+        debug!(%host, "datagram is too short for a udp header, ignoring");
+        return None;
+    };
+
+    let port = udp_packet.get_source();
+    debug!(%host, port, "got port");
+
+    if udp_packet.get_destination() != source_ports.pick(key, src_ip_addr, host, port) {
+        debug!(%host, port, "datagram isn't addressed to our source port, ignoring");
+        return None;
+    }
+
+    Some(((host, port), PortStatus::Open, Reason::UdpResponse))
+}
+
+/// Checks that an SCTP packet is a reply to one of our INITs, which is
+/// the case if it echoes our initiate tag as its verification tag, and
+/// maps its first chunk to the port status it implies.
+fn sctp_reply(
+    host: IpAddr,
+    packet: &[u8],
+    src_ip_addr: IpAddr,
+    key: &RandomState,
+    source_ports: SourcePorts,
+) -> Option<Verdict> {
+    const INIT_ACK: u8 = 2;
+    const ABORT: u8 = 6;
+
+    if packet.len() < SCTP_HDR_LEN as usize + 4 {
+        debug!(%host, "packet is too short for an sctp chunk, ignoring");
+        return None;
+    }
+
+    let port = u16::from_be_bytes([packet[0], packet[1]]);
+    let dest_port = u16::from_be_bytes([packet[2], packet[3]]);
+    let verification_tag = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+    let chunk_type = packet[SCTP_HDR_LEN as usize];
+    debug!(%host, port, "got port");
+
+    let src_port = source_ports.pick(key, src_ip_addr, host, port);
+    if dest_port != src_port
+        || verification_tag != sequence_number(key, src_ip_addr, src_port, host, port)
+    {
+        debug!(%host, port, "packet isn't a reply to our init, ignoring");
+        return None;
+    }
+
+    match chunk_type {
+        INIT_ACK => Some(((host, port), PortStatus::Open, Reason::InitAck)),
+        ABORT => Some(((host, port), PortStatus::Closed, Reason::Abort)),
+        _ => {
+            warn!(%host, port, chunk_type, "chunk wasn't expected");
+            None
+        }
+    }
+}
+
+/// Parses an ICMP destination unreachable message and finds the probe it
+/// refers to by looking at the original IP header + the first 8 bytes of
+/// the transport header, which the message quotes. Returns the probe along
+/// with the ICMP code.
+fn icmp_unreachable(
+    packet: &[u8],
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+    source_ports: SourcePorts,
+    scan_type: ScanType,
+) -> Option<(Probe, u8)> {
+    let (_, icmp) = ipv4_payload(packet, IpNextHeaderProtocols::Icmp)?;
+    let icmp_packet = DestinationUnreachablePacket::new(icmp)?;
+
+    if icmp_packet.get_icmp_type() != IcmpTypes::DestinationUnreachable {
+        return None;
+    }
+
+    let quoted = icmp_packet.payload();
+    let original = Ipv4Packet::new(quoted)?;
+    let header_len = original.get_header_length() as usize * 4;
+
+    if header_len < IP_HDR_LEN as usize {
+        return None;
+    }
+
+    let host = IpAddr::V4(original.get_destination());
+    let src_ip_addr = src_ip_addrs.get(&host)?;
+
+    /* IP protocol probes have no transport header, so the protocol is the
+     * "port" and the cookie sits in the identification field instead. */
+    if scan_type == ScanType::IpProto {
+        let port = original.get_next_level_protocol().0 as Port;
+        let cookie = sequence_number(key, *src_ip_addr, SOURCE_PORT, host, port) as u16;
+
+        if original.get_identification() != cookie {
+            debug!(%host, port, "icmp message doesn't quote our probe, ignoring");
+            return None;
+        }
+
+        let code = icmp_packet.get_icmp_code().0;
+        debug!(%host, port, code, "got icmp unreachable");
+
+        return Some(((host, port), code));
+    }
+
+    if original.get_next_level_protocol() != scan_type.protocol() {
+        return None;
+    }
+
+    let transport = quoted.get(header_len..)?;
+    let port = quoted_port(transport, host, *src_ip_addr, key, source_ports, scan_type)?;
+
+    let code = icmp_packet.get_icmp_code().0;
+    debug!(%host, port, code, "got icmp unreachable");
+
+    Some(((host, port), code))
+}
+
+/// Does the same for an ICMPv6 destination unreachable message, which comes
+/// without the IPv6 header, since raw IPv6 sockets strip it. The IPv6 header
+/// it quotes is the one of our probe, so there are no extension headers.
+fn icmpv6_unreachable(
+    packet: &[u8],
+    src_ip_addrs: &HashMap<IpAddr, IpAddr>,
+    key: &RandomState,
+    source_ports: SourcePorts,
+    scan_type: ScanType,
+) -> Option<(Probe, u8)> {
+    let icmp_packet = Icmpv6Packet::new(packet)?;
+
+    if icmp_packet.get_icmpv6_type() != Icmpv6Types::DestinationUnreachable {
+        return None;
+    }
+
+    /* the quoted packet follows 4 unused bytes */
+    let quoted = icmp_packet.payload().get(4..)?;
+    let original = Ipv6Packet::new(quoted)?;
+
+    if original.get_next_header() != scan_type.protocol() {
+        return None;
+    }
+
+    let host = IpAddr::V6(original.get_destination());
+    let src_ip_addr = src_ip_addrs.get(&host)?;
+
+    let transport = quoted.get(IPV6_HDR_LEN as usize..)?;
+    let port = quoted_port(transport, host, *src_ip_addr, key, source_ports, scan_type)?;
+
+    let code = icmp_packet.get_icmpv6_code().0;
+    debug!(%host, port, code, "got icmpv6 unreachable");
+
+    Some(((host, port), code))
+}
+
+/// Finds the port of the probe to `host` that an ICMP error quotes the
+/// transport header of. The quoted IP header is followed by at least 8
+/// bytes of the original transport header, which is just enough for the
+/// ports and, for TCP, the sequence number, so it's picked apart by hand
+/// instead of with TcpPacket.
+fn quoted_port(
+    transport: &[u8],
+    host: IpAddr,
+    src_ip_addr: IpAddr,
+    key: &RandomState,
+    source_ports: SourcePorts,
+    scan_type: ScanType,
+) -> Option<Port> {
+    let transport = transport.get(..8)?;
+    let src_port = u16::from_be_bytes([transport[0], transport[1]]);
+    let port = u16::from_be_bytes([transport[2], transport[3]]);
+
+    let ours = src_port == source_ports.pick(key, src_ip_addr, host, port)
+        && match scan_type.protocol() {
+            IpNextHeaderProtocols::Tcp => {
+                let sequence =
+                    u32::from_be_bytes([transport[4], transport[5], transport[6], transport[7]]);
+                sequence == sequence_number(key, src_ip_addr, src_port, host, port)
             }
-            _ => {
-                warn!(port, "port wasn't expected");
+            _ => true,
+        };
+
+    if !ours {
+        debug!(%host, port, "icmp message doesn't quote our probe, ignoring");
+        return None;
+    }
+
+    Some(port)
+}
+
+/// Receives a verdict from the connect() workers if there are any,
+/// otherwise never resolves, just like `recv_on`.
+async fn recv_verdict(verdicts: Option<&mut Receiver<Verdict>>) -> Verdict {
+    match verdicts {
+        Some(verdicts) => match verdicts.recv().await {
+            Some(verdict) => verdict,
+            None => std::future::pending().await,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::tcp_reply;
+    use crate::{
+        net::{sequence_number, SourcePorts, SOURCE_PORT, TCP_HDR_LEN},
+        scan::ScanType,
+        Port, PortStatus, Reason, Verdict,
+    };
+    use pnet::packet::tcp::{MutableTcpPacket, TcpFlags};
+    use std::{
+        collections::hash_map::RandomState,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    const SRC_IP_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    const HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const PORT: Port = 443;
+
+    /// A segment from `host`:`port` to our source port.
+    struct Segment {
+        host: IpAddr,
+        port: Port,
+        dest: Port,
+        flags: u8,
+        sequence: u32,
+        acknowledgement: u32,
+        window: u16,
+    }
+
+    impl Segment {
+        fn new(flags: u8) -> Self {
+            Self {
+                host: HOST,
+                port: PORT,
+                dest: SOURCE_PORT,
+                flags,
+                sequence: 0x1234_5678,
+                acknowledgement: 0,
+                window: 0,
             }
         }
+
+        fn reply(&self, key: &RandomState, scan_type: ScanType) -> Option<Verdict> {
+            let mut buf = [0u8; TCP_HDR_LEN as usize];
+            let mut packet = MutableTcpPacket::new(&mut buf).unwrap();
+            packet.set_source(self.port);
+            packet.set_destination(self.dest);
+            packet.set_flags(self.flags);
+            packet.set_sequence(self.sequence);
+            packet.set_acknowledgement(self.acknowledgement);
+            packet.set_window(self.window);
+            packet.set_data_offset(5);
+
+            let source_ports = SourcePorts::default();
+            tcp_reply(self.host, &buf, SRC_IP_ADDR, key, source_ports, scan_type)
+        }
     }
 
-    let closed_count = status
-        .iter()
-        .filter(|(_, info)| info.status == PortStatus::Closed)
-        .count();
+    fn cookie(key: &RandomState) -> u32 {
+        sequence_number(key, SRC_IP_ADDR, SOURCE_PORT, HOST, PORT)
+    }
 
-    println!("ports closed: {closed_count}");
+    fn status(verdict: Option<Verdict>) -> Option<(PortStatus, Reason)> {
+        verdict.map(|((host, port), status, reason)| {
+            assert_eq!((host, port), (HOST, PORT));
+            (status, reason)
+        })
+    }
 
-    status
-        .iter_mut()
-        .filter(|(_, info)| info.retried >= max_retries)
-        .for_each(|(_, info)| info.status = PortStatus::Filtered);
+    /* each scan type has to see its own cookie acknowledged, and nothing
+     * else will do */
+    #[test]
+    fn acknowledgement_cookie() {
+        let key = RandomState::new();
+        let rst_ack = TcpFlags::RST | TcpFlags::ACK;
 
-    let filtered_count = status
-        .iter()
-        .filter(|(_, info)| info.status == PortStatus::Filtered)
-        .count();
+        for (scan_type, flags, expected) in [
+            (
+                ScanType::Syn,
+                TcpFlags::SYN | TcpFlags::ACK,
+                PortStatus::Open,
+            ),
+            (ScanType::Syn, rst_ack, PortStatus::Closed),
+            (ScanType::Fin, rst_ack, PortStatus::Closed),
+            (ScanType::Null, rst_ack, PortStatus::Closed),
+            (ScanType::Xmas, rst_ack, PortStatus::Closed),
+        ] {
+            let mut segment = Segment::new(flags);
+
+            let expected_ack = scan_type.expected_ack(cookie(&key));
+            segment.acknowledgement = expected_ack;
+            let verdict = status(segment.reply(&key, scan_type));
+            assert_eq!(
+                verdict.map(|(status, _)| status),
+                Some(expected),
+                "{scan_type}"
+            );
+
+            for off_by in [1, u32::MAX, 1 << 31] {
+                segment.acknowledgement = expected_ack.wrapping_add(off_by);
+                assert_eq!(segment.reply(&key, scan_type), None, "{scan_type}");
+            }
+        }
+    }
 
-    println!("ports filtered: {filtered_count}");
+    /* the RST to an ACK probe takes its sequence number from the
+     * acknowledgement number of the probe, which is the cookie */
+    #[test]
+    fn sequence_cookie() {
+        let key = RandomState::new();
 
-    let retried_more_than_once_count = status.iter().filter(|(_, info)| info.retried > 1).count();
+        for (scan_type, window, expected) in [
+            (ScanType::Ack, 0, PortStatus::Unfiltered),
+            (ScanType::Window, 0, PortStatus::Closed),
+            (ScanType::Window, 1024, PortStatus::Open),
+        ] {
+            let mut segment = Segment::new(TcpFlags::RST);
+            segment.window = window;
 
-    println!("ports retried more than once: {retried_more_than_once_count}");
+            segment.sequence = cookie(&key);
+            let verdict = status(segment.reply(&key, scan_type));
+            assert_eq!(verdict, Some((expected, Reason::Reset)), "{scan_type}");
 
-    info!("exiting");
+            segment.sequence = cookie(&key).wrapping_add(1);
+            assert_eq!(segment.reply(&key, scan_type), None, "{scan_type}");
+        }
+    }
 
-    Ok(())
+    /* a RST without the ACK bit to a probe without it carries nothing
+     * to check, so it's taken at its word */
+    #[test]
+    fn bare_reset() {
+        let key = RandomState::new();
+
+        for scan_type in [ScanType::Syn, ScanType::Fin, ScanType::Null, ScanType::Xmas] {
+            let verdict = status(Segment::new(TcpFlags::RST).reply(&key, scan_type));
+            assert_eq!(
+                verdict,
+                Some((PortStatus::Closed, Reason::Reset)),
+                "{scan_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_destination_port() {
+        let key = RandomState::new();
+
+        let mut segment = Segment::new(TcpFlags::SYN | TcpFlags::ACK);
+        segment.acknowledgement = ScanType::Syn.expected_ack(cookie(&key));
+        segment.dest = SOURCE_PORT + 1;
+        assert_eq!(segment.reply(&key, ScanType::Syn), None);
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let key = RandomState::new();
+
+        let verdict = status(Segment::new(TcpFlags::SYN).reply(&key, ScanType::Syn));
+        assert_eq!(verdict, Some((PortStatus::Open, Reason::Syn)));
+    }
+
+    /* our own SYN, read back off the raw socket */
+    #[test]
+    fn looped_back_syn() {
+        let key = RandomState::new();
+
+        let mut segment = Segment::new(TcpFlags::SYN);
+        segment.sequence = cookie(&key);
+        assert_eq!(segment.reply(&key, ScanType::Syn), None);
+
+        /* scanning our own source port on our own address */
+        let mut segment = Segment::new(TcpFlags::SYN);
+        segment.host = SRC_IP_ADDR;
+        segment.port = SOURCE_PORT;
+        assert_eq!(segment.reply(&key, ScanType::Syn), None);
+
+        /* ECN doesn't make it any less of a bare SYN */
+        let mut segment = Segment::new(TcpFlags::SYN | TcpFlags::ECE | TcpFlags::CWR);
+        segment.sequence = cookie(&key);
+        assert_eq!(segment.reply(&key, ScanType::Syn), None);
+    }
+
+    #[test]
+    fn ecn_syn_ack() {
+        let key = RandomState::new();
+
+        let mut segment = Segment::new(TcpFlags::SYN | TcpFlags::ACK | TcpFlags::ECE);
+        segment.acknowledgement = ScanType::Syn.expected_ack(cookie(&key));
+        let verdict = status(segment.reply(&key, ScanType::Syn));
+        assert_eq!(verdict, Some((PortStatus::Open, Reason::SynAck)));
+    }
 }
@@ -0,0 +1,373 @@
+use crate::net::get_ipv6_source_addr;
+use anyhow::{bail, Context, Result};
+use pnet::{
+    datalink::{self, NetworkInterface},
+    ipnetwork::IpNetwork,
+};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+const IPV4_ROUTES: &str = "/proc/net/route";
+const IPV6_ROUTES: &str = "/proc/net/ipv6_route";
+
+/* the route flags we care about, from linux/route.h */
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+
+/// The way to a target: the interface the probes leave through, the source
+/// address they go out with, and the gateway they're handed to, unless the
+/// target is directly attached.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub interface: String,
+    pub src_ip_addr: IpAddr,
+    pub gateway: Option<IpAddr>,
+}
+
+/// A snapshot of the kernel's main routing tables, read from procfs so
+/// that neither iproute2 nor a default route are needed.
+pub struct RoutingTable {
+    entries: Vec<Entry>,
+    interfaces: Vec<NetworkInterface>,
+}
+
+struct Entry {
+    interface: String,
+    network: IpNetwork,
+    gateway: Option<IpAddr>,
+    metric: u32,
+}
+
+impl RoutingTable {
+    /// Reads the routes of both address families. A kernel without IPv6
+    /// has no IPv6 routes to read, which only matters for IPv6 targets.
+    pub fn read() -> Result<Self> {
+        let ipv4 =
+            fs::read_to_string(IPV4_ROUTES).with_context(|| format!("reading {IPV4_ROUTES}"))?;
+        let ipv6 = fs::read_to_string(IPV6_ROUTES).unwrap_or_default();
+
+        Ok(Self::parse(&ipv4, &ipv6, datalink::interfaces()))
+    }
+
+    /// Makes a table out of what the two procfs files hold.
+    fn parse(ipv4: &str, ipv6: &str, interfaces: Vec<NetworkInterface>) -> Self {
+        let entries = ipv4
+            .lines()
+            .skip(1) /* the header */
+            .filter_map(parse_ipv4_line)
+            .chain(ipv6.lines().filter_map(parse_ipv6_line))
+            .collect();
+
+        Self {
+            entries,
+            interfaces,
+        }
+    }
+
+    /// Tells whether there's an interface by that name.
+    pub fn has_interface(&self, name: &str) -> bool {
+        self.interfaces
+            .iter()
+            .any(|interface| interface.name == name)
+    }
+
+    /// Looks up the route to `dest` the way the kernel would: the most
+    /// specific one that matches wins, and the lowest metric breaks ties.
+    /// Given an interface, only the routes through it are considered.
+    pub fn lookup(&self, dest: IpAddr, interface: Option<&str>) -> Result<Route> {
+        let through = |name: &str| interface.is_none_or(|interface| interface == name);
+
+        /* 127.0.0.0/8 only shows up in the local table, which isn't in procfs */
+        if let IpAddr::V4(ipv4) = dest {
+            if ipv4.is_loopback() {
+                let lo = self.interfaces.iter().find(|i| i.is_loopback());
+                if let Some(lo) = lo.filter(|lo| through(&lo.name)) {
+                    return Ok(Route {
+                        interface: lo.name.clone(),
+                        src_ip_addr: dest,
+                        gateway: None,
+                    });
+                }
+            }
+        }
+
+        let Some(entry) = self
+            .entries
+            .iter()
+            .filter(|entry| entry.network.contains(dest) && through(&entry.interface))
+            .max_by_key(|entry| (entry.network.prefix(), u32::MAX - entry.metric))
+        else {
+            match interface {
+                Some(interface) => bail!("no route to {dest} through {interface}"),
+                None => bail!("no route to {dest}"),
+            }
+        };
+
+        /* IPv6 source address selection (RFC 6724) is best left to the kernel,
+         * but it doesn't know which interface we're going to send through */
+        let src_ip_addr = match dest {
+            IpAddr::V6(dest) if interface.is_none() => IpAddr::V6(get_ipv6_source_addr(dest)?),
+            _ => self.source_addr(entry, dest)?,
+        };
+
+        Ok(Route {
+            interface: entry.interface.clone(),
+            src_ip_addr,
+            gateway: entry.gateway,
+        })
+    }
+
+    /// Picks our address on the interface of the route, preferring the one
+    /// on the same subnet as the next hop, should it have more than one,
+    /// and otherwise one that isn't only good for talking to ourselves.
+    /// IPv6 link-local addresses are only picked for link-local targets.
+    fn source_addr(&self, entry: &Entry, dest: IpAddr) -> Result<IpAddr> {
+        let Some(interface) = self.interfaces.iter().find(|i| i.name == entry.interface) else {
+            bail!(
+                "the route to {dest} goes through {}, which is gone",
+                entry.interface
+            );
+        };
+
+        let link_local = |ip: IpAddr| match ip {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(ip) => ip.is_unicast_link_local(),
+        };
+        let next_hop = entry.gateway.unwrap_or(dest);
+        let addrs = || {
+            interface.ips.iter().filter(|ip_net| {
+                ip_net.is_ipv4() == dest.is_ipv4() && (!link_local(ip_net.ip()) || link_local(dest))
+            })
+        };
+
+        match addrs()
+            .find(|ip_net| ip_net.contains(next_hop))
+            .or_else(|| addrs().find(|ip_net| !ip_net.ip().is_loopback()))
+            .or_else(|| addrs().next())
+        {
+            Some(ip_net) => Ok(ip_net.ip()),
+            None => bail!("{} has no address to reach {dest} from", interface.name),
+        }
+    }
+}
+
+/// Parses a line of /proc/net/route, where the addresses are hex dumps of
+/// the in-memory (so on x86, little-endian) representation:
+/// "eth0  000200C0  00000000  0001  0  0  0  00FFFFFF  0  0  0".
+fn parse_ipv4_line(line: &str) -> Option<Entry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [interface, destination, gateway, flags, _, _, metric, mask, ..] = fields[..] else {
+        return None;
+    };
+
+    let hex = |field: &str| u32::from_str_radix(field, 16).ok();
+    let addr = |field: &str| hex(field).map(|n| Ipv4Addr::from(n.to_ne_bytes()));
+
+    let flags = hex(flags)?;
+    if flags & RTF_UP == 0 || flags & RTF_REJECT != 0 {
+        return None;
+    }
+
+    let prefix = hex(mask)?.count_ones() as u8;
+    let network = IpNetwork::new(IpAddr::V4(addr(destination)?), prefix).ok()?;
+    let gateway = addr(gateway)?;
+
+    Some(Entry {
+        interface: interface.to_string(),
+        network,
+        gateway: (!gateway.is_unspecified()).then_some(IpAddr::V4(gateway)),
+        metric: metric.parse().ok()?,
+    })
+}
+
+/// Parses a line of /proc/net/ipv6_route, whose addresses are in network
+/// order: "dest prefix_len src src_prefix_len next_hop metric refcnt use
+/// flags interface", all of it in hex but the interface.
+fn parse_ipv6_line(line: &str) -> Option<Entry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [destination, prefix, _, _, next_hop, metric, _, _, flags, interface] = fields[..] else {
+        return None;
+    };
+
+    let hex = |field: &str| u32::from_str_radix(field, 16).ok();
+    let addr = |field: &str| u128::from_str_radix(field, 16).ok().map(Ipv6Addr::from);
+
+    let flags = hex(flags)?;
+    if flags & RTF_UP == 0 || flags & RTF_REJECT != 0 {
+        return None;
+    }
+
+    let network = IpNetwork::new(IpAddr::V6(addr(destination)?), hex(prefix)? as u8).ok()?;
+    let next_hop = addr(next_hop)?;
+
+    Some(Entry {
+        interface: interface.to_string(),
+        network,
+        gateway: (!next_hop.is_unspecified()).then_some(IpAddr::V6(next_hop)),
+        metric: hex(metric)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_ipv4_line, parse_ipv6_line, RoutingTable};
+    use pnet::{datalink::NetworkInterface, ipnetwork::IpNetwork};
+    use std::net::IpAddr;
+
+    /* the IPv4 addresses are dumped in host order, these as on x86 */
+    const IPV4_ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t010200C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth1\t0000000A\t00000000\t0001\t0\t0\t100\t000000FF\t0\t0\t0
+eth0\t0000010A\t010200C0\t0003\t0\t0\t0\t0000FFFF\t0\t0\t0
+eth1\t0000020A\t00000000\t0001\t0\t0\t100\t0000FFFF\t0\t0\t0
+eth0\t0000020A\t010200C0\t0003\t0\t0\t50\t0000FFFF\t0\t0\t0
+eth1\t0A00A8C0\t00000000\t0005\t0\t0\t0\tFFFFFFFF\t0\t0\t0
+eth0\t007100CB\t00000000\t0201\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+
+    const IPV6_ROUTES: &str = "\
+fd000000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000002 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     eth0
+20010db8000000000000000000000001 80 00000000000000000000000000000000 00 00000000000000000000000000000000 00000000 00000001 00000000 00000001     eth1
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+";
+
+    fn interface(name: &str, index: u32, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac: None,
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            flags: 0,
+        }
+    }
+
+    fn table() -> RoutingTable {
+        RoutingTable::parse(
+            IPV4_ROUTES,
+            IPV6_ROUTES,
+            vec![
+                interface("eth0", 2, &["192.0.2.2/24", "fd00::2/64", "fe80::2/64"]),
+                interface("eth1", 3, &["10.0.0.5/8", "2001:db8::5/64"]),
+            ],
+        )
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn ipv4_lines() {
+        let default = parse_ipv4_line("eth0\t00000000\t010200C0\t0003\t0\t0\t0\t00000000\t0\t0\t0");
+        let default = default.unwrap();
+        assert_eq!(default.interface, "eth0");
+        assert_eq!(default.network, "0.0.0.0/0".parse::<IpNetwork>().unwrap());
+        assert_eq!(default.gateway, Some(ip("192.0.2.1")));
+        assert_eq!(default.metric, 0);
+
+        let host = parse_ipv4_line("eth1\t0A00A8C0\t00000000\t0005\t0\t0\t7\tFFFFFFFF\t0\t0\t0");
+        let host = host.unwrap();
+        assert_eq!(
+            host.network,
+            "192.168.0.10/32".parse::<IpNetwork>().unwrap()
+        );
+        assert_eq!(host.gateway, None);
+        assert_eq!(host.metric, 7);
+
+        /* rejected, and down */
+        assert!(
+            parse_ipv4_line("eth0\t007100CB\t00000000\t0201\t0\t0\t0\t00FFFFFF\t0\t0\t0").is_none()
+        );
+        assert!(
+            parse_ipv4_line("eth0\t007100CB\t00000000\t0000\t0\t0\t0\t00FFFFFF\t0\t0\t0").is_none()
+        );
+        assert!(parse_ipv4_line("eth0\t007100CB").is_none());
+    }
+
+    #[test]
+    fn ipv6_lines() {
+        let mut lines = IPV6_ROUTES.lines().map(parse_ipv6_line);
+
+        let subnet = lines.next().flatten().unwrap();
+        assert_eq!(subnet.network, "fd00::/64".parse::<IpNetwork>().unwrap());
+        assert_eq!(subnet.gateway, None);
+        assert_eq!(subnet.metric, 0x100);
+
+        let default = lines.next().flatten().unwrap();
+        assert_eq!(default.network, "::/0".parse::<IpNetwork>().unwrap());
+        assert_eq!(default.gateway, Some(ip("fd00::1")));
+        assert_eq!(default.metric, 0x400);
+
+        let host = lines.next().flatten().unwrap();
+        assert_eq!(host.interface, "eth1");
+        assert_eq!(
+            host.network,
+            "2001:db8::1/128".parse::<IpNetwork>().unwrap()
+        );
+
+        /* the unreachable default route the kernel keeps on lo */
+        assert!(lines.next().flatten().is_none());
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn lookup() {
+        let table = table();
+
+        /* the default route */
+        let route = table.lookup(ip("198.51.100.7"), None).unwrap();
+        assert_eq!(route.interface, "eth0");
+        assert_eq!(route.gateway, Some(ip("192.0.2.1")));
+        assert_eq!(route.src_ip_addr, ip("192.0.2.2"));
+
+        /* directly attached */
+        let route = table.lookup(ip("192.0.2.77"), None).unwrap();
+        assert_eq!((route.interface.as_str(), route.gateway), ("eth0", None));
+
+        /* the /16 through eth0 beats the /8 through eth1 */
+        let route = table.lookup(ip("10.1.2.3"), None).unwrap();
+        assert_eq!(route.interface, "eth0");
+        assert_eq!(route.gateway, Some(ip("192.0.2.1")));
+        let route = table.lookup(ip("10.3.2.1"), None).unwrap();
+        assert_eq!(route.interface, "eth1");
+        assert_eq!(route.src_ip_addr, ip("10.0.0.5"));
+
+        /* two /16s, and the lower metric wins, unless the interface is given */
+        let route = table.lookup(ip("10.2.0.1"), None).unwrap();
+        assert_eq!(route.interface, "eth0");
+        let route = table.lookup(ip("10.2.0.1"), Some("eth1")).unwrap();
+        assert_eq!((route.interface.as_str(), route.gateway), ("eth1", None));
+
+        /* the host route */
+        let route = table.lookup(ip("192.168.0.10"), None).unwrap();
+        assert_eq!(route.interface, "eth1");
+        assert!(table.lookup(ip("192.168.0.11"), Some("eth1")).is_err());
+
+        /* the rejected route is left out, so the default route takes over */
+        let route = table.lookup(ip("203.0.113.1"), None).unwrap();
+        assert_eq!(route.gateway, Some(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn lookup_ipv6() {
+        let table = table();
+
+        /* given the interface, the source address isn't up to the kernel */
+        let route = table.lookup(ip("2001:db8:1::1"), Some("eth0")).unwrap();
+        assert_eq!(route.gateway, Some(ip("fd00::1")));
+        assert_eq!(route.src_ip_addr, ip("fd00::2"));
+
+        let route = table.lookup(ip("2001:db8::1"), Some("eth1")).unwrap();
+        assert_eq!(route.gateway, None);
+        assert_eq!(route.src_ip_addr, ip("2001:db8::5"));
+
+        assert!(table.lookup(ip("2001:db8:1::1"), Some("eth1")).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{Port, PortStatus, Reason};
+use anyhow::{bail, Result};
+use pnet::packet::{
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    tcp::TcpFlags,
+};
+
+/// ICMP destination unreachable codes that mean a firewall is in the way.
+const FILTERED_ICMP_CODES: [u8; 6] = [1, 2, 3, 9, 10, 13];
+
+/// ICMP protocol unreachable, which a host answers with when it doesn't
+/// speak the protocol of the probe.
+const PROTOCOL_UNREACHABLE: u8 = 2;
+
+/// ICMP port unreachable, which a closed UDP port answers with, and which
+/// tells an IP protocol scan that the host speaks the protocol.
+const PORT_UNREACHABLE: u8 = 3;
+
+/// ICMPv6 destination unreachable codes (RFC 4443) that mean a firewall
+/// is in the way: administratively prohibited, address unreachable and,
+/// for anything but UDP, port unreachable, like ICMP's.
+const FILTERED_ICMPV6_CODES: [u8; 3] = [1, 3, 4];
+
+/// ICMPv6 port unreachable, which a closed UDP port answers with.
+const ICMPV6_PORT_UNREACHABLE: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanType {
+    Syn,
+    /// RFC 793 behavioural scans: a closed port answers with RST,
+    /// an open one drops the probe on the floor.
+    Fin,
+    Null,
+    Xmas,
+    /// Firewall rule mapping: any RST means the probe got through.
+    Ack,
+    /// Like ACK, but some stacks advertise a non-zero window in the RST
+    /// from an open port, which tells open and closed ports apart.
+    Window,
+    /// UDP datagrams, with a payload tailored to the port where possible.
+    Udp,
+    /// A full handshake through the kernel, for when raw sockets are off limits.
+    Connect,
+    /// SCTP INIT, the SCTP counterpart of the SYN scan.
+    Sctp,
+    /// Enumerates the IP protocols a host supports rather than ports: the
+    /// "ports" are protocol numbers, sent as bare IPv4 headers.
+    IpProto,
+}
+
+impl ScanType {
+    /// Whether the probes are crafted by hand and sent over raw sockets.
+    pub fn needs_raw_sockets(&self) -> bool {
+        !matches!(self, Self::Connect)
+    }
+
+    /// The transport protocol the probes are sent over.
+    pub fn protocol(&self) -> IpNextHeaderProtocol {
+        match self {
+            Self::Udp => IpNextHeaderProtocols::Udp,
+            Self::Sctp => IpNextHeaderProtocols::Sctp,
+            _ => IpNextHeaderProtocols::Tcp,
+        }
+    }
+
+    /// The name of the protocol the ports belong to, the way nmap puts it.
+    pub fn protocol_name(&self) -> &'static str {
+        match self {
+            Self::IpProto => "ip",
+            _ => match self.protocol() {
+                IpNextHeaderProtocols::Udp => "udp",
+                IpNextHeaderProtocols::Sctp => "sctp",
+                _ => "tcp",
+            },
+        }
+    }
+
+    /// The protocol in the IP header of a probe to the port, which is the
+    /// port itself for the IP protocol scan.
+    pub fn ip_protocol(&self, port: Port) -> IpNextHeaderProtocol {
+        match self {
+            Self::IpProto => IpNextHeaderProtocol(port as u8),
+            _ => self.protocol(),
+        }
+    }
+
+    /// The flags set on the probes, for the TCP scan types.
+    pub fn flags(&self) -> u8 {
+        match self {
+            Self::Syn | Self::Connect => TcpFlags::SYN,
+            Self::Fin => TcpFlags::FIN,
+            Self::Null => 0,
+            Self::Xmas => TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG,
+            Self::Ack | Self::Window => TcpFlags::ACK,
+            Self::Udp | Self::Sctp | Self::IpProto => 0,
+        }
+    }
+
+    /// The acknowledgement number a reply to a probe with the given
+    /// sequence number carries. SYN and FIN each occupy one sequence number.
+    pub fn expected_ack(&self, sequence: u32) -> u32 {
+        match self.flags() & (TcpFlags::SYN | TcpFlags::FIN) {
+            0 => sequence,
+            _ => sequence.wrapping_add(1),
+        }
+    }
+
+    /// The sequence number a reply without the ACK bit carries, if any.
+    /// A RST to a probe with the ACK bit takes its sequence number from
+    /// our acknowledgement number, which is set to the probe's sequence.
+    pub fn expected_seq(&self, sequence: u32) -> Option<u32> {
+        match self.flags() & TcpFlags::ACK {
+            0 => None,
+            _ => Some(sequence),
+        }
+    }
+
+    /// Maps the flags and window of a reply to the port status they imply.
+    /// Only the bits that matter are looked at, so ECN (ECE, CWR) and other
+    /// bits that stacks and middleboxes like to set don't get in the way.
+    pub fn classify(&self, flags: u8, window: u16) -> Option<(PortStatus, Reason)> {
+        match self {
+            Self::Syn => {
+                if flags & TcpFlags::RST != 0 {
+                    Some((PortStatus::Closed, Reason::Reset))
+                } else if flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK != 0 {
+                    Some((PortStatus::Open, Reason::SynAck))
+                } else if flags & TcpFlags::SYN != 0 {
+                    /* a bare SYN back means the target went for a simultaneous open */
+                    Some((PortStatus::Open, Reason::Syn))
+                } else {
+                    None
+                }
+            }
+            Self::Fin | Self::Null | Self::Xmas => {
+                if flags & TcpFlags::RST != 0 {
+                    Some((PortStatus::Closed, Reason::Reset))
+                } else {
+                    None
+                }
+            }
+            Self::Ack => {
+                if flags & TcpFlags::RST != 0 {
+                    Some((PortStatus::Unfiltered, Reason::Reset))
+                } else {
+                    None
+                }
+            }
+            Self::Window => {
+                if flags & TcpFlags::RST != 0 && window > 0 {
+                    Some((PortStatus::Open, Reason::Reset))
+                } else if flags & TcpFlags::RST != 0 {
+                    Some((PortStatus::Closed, Reason::Reset))
+                } else {
+                    None
+                }
+            }
+            Self::Udp | Self::Connect | Self::Sctp | Self::IpProto => None,
+        }
+    }
+
+    /// Maps the code of an ICMP destination unreachable message that
+    /// quotes one of our probes to the port status it implies.
+    pub fn classify_icmp(&self, code: u8) -> Option<(PortStatus, Reason)> {
+        let reason = Reason::IcmpUnreachable { code };
+
+        match self {
+            Self::Udp if code == PORT_UNREACHABLE => Some((PortStatus::Closed, reason)),
+            Self::IpProto if code == PROTOCOL_UNREACHABLE => Some((PortStatus::Closed, reason)),
+            Self::IpProto if code == PORT_UNREACHABLE => Some((PortStatus::Open, reason)),
+            _ if FILTERED_ICMP_CODES.contains(&code) => Some((PortStatus::Filtered, reason)),
+            _ => None,
+        }
+    }
+
+    /// Does the same for ICMPv6, whose codes are different.
+    pub fn classify_icmpv6(&self, code: u8) -> Option<(PortStatus, Reason)> {
+        let reason = Reason::Icmpv6Unreachable { code };
+
+        match self {
+            Self::Udp if code == ICMPV6_PORT_UNREACHABLE => Some((PortStatus::Closed, reason)),
+            _ if FILTERED_ICMPV6_CODES.contains(&code) => Some((PortStatus::Filtered, reason)),
+            _ => None,
+        }
+    }
+
+    /// The status of a port that never answered.
+    pub fn no_response(&self) -> PortStatus {
+        match self {
+            Self::Syn | Self::Ack | Self::Window | Self::Connect | Self::Sctp => {
+                PortStatus::Filtered
+            }
+            Self::Fin | Self::Null | Self::Xmas | Self::Udp | Self::IpProto => {
+                PortStatus::OpenFiltered
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ScanType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syn => write!(f, "syn"),
+            Self::Fin => write!(f, "fin"),
+            Self::Null => write!(f, "null"),
+            Self::Xmas => write!(f, "xmas"),
+            Self::Ack => write!(f, "ack"),
+            Self::Window => write!(f, "window"),
+            Self::Udp => write!(f, "udp"),
+            Self::Connect => write!(f, "connect"),
+            Self::Sctp => write!(f, "sctp"),
+            Self::IpProto => write!(f, "ip"),
+        }
+    }
+}
+
+impl std::str::FromStr for ScanType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "syn" => Ok(Self::Syn),
+            "fin" => Ok(Self::Fin),
+            "null" => Ok(Self::Null),
+            "xmas" => Ok(Self::Xmas),
+            "ack" => Ok(Self::Ack),
+            "window" => Ok(Self::Window),
+            "udp" => Ok(Self::Udp),
+            "connect" => Ok(Self::Connect),
+            "sctp" => Ok(Self::Sctp),
+            "ip" => Ok(Self::IpProto),
+            _ => bail!("expected one of syn, fin, null, xmas, ack, window, udp, connect, sctp, ip"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ScanType;
+    use crate::{PortStatus, Reason};
+    use pnet::packet::tcp::TcpFlags;
+
+    const TCP_SCANS: [ScanType; 6] = [
+        ScanType::Syn,
+        ScanType::Fin,
+        ScanType::Null,
+        ScanType::Xmas,
+        ScanType::Ack,
+        ScanType::Window,
+    ];
+
+    /* SYN and FIN take up a sequence number, the rest don't */
+    #[test]
+    fn expected_ack() {
+        for scan_type in TCP_SCANS {
+            let expected = match scan_type {
+                ScanType::Syn | ScanType::Fin | ScanType::Xmas => 1001,
+                _ => 1000,
+            };
+            assert_eq!(scan_type.expected_ack(1000), expected, "{scan_type}");
+        }
+
+        assert_eq!(ScanType::Syn.expected_ack(u32::MAX), 0);
+        assert_eq!(ScanType::Connect.expected_ack(1000), 1001);
+    }
+
+    /* only the replies to probes with the ACK bit echo our numbers back
+     * as their sequence number */
+    #[test]
+    fn expected_seq() {
+        for scan_type in TCP_SCANS {
+            let expected = match scan_type {
+                ScanType::Ack | ScanType::Window => Some(1000),
+                _ => None,
+            };
+            assert_eq!(scan_type.expected_seq(1000), expected, "{scan_type}");
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        let rst_ack = TcpFlags::RST | TcpFlags::ACK;
+
+        assert_eq!(
+            ScanType::Syn.classify(syn_ack, 0),
+            Some((PortStatus::Open, Reason::SynAck))
+        );
+        assert_eq!(
+            ScanType::Syn.classify(TcpFlags::SYN, 0),
+            Some((PortStatus::Open, Reason::Syn))
+        );
+        assert_eq!(
+            ScanType::Syn.classify(rst_ack, 0),
+            Some((PortStatus::Closed, Reason::Reset))
+        );
+        assert_eq!(ScanType::Syn.classify(TcpFlags::ACK, 0), None);
+
+        for scan_type in [ScanType::Fin, ScanType::Null, ScanType::Xmas] {
+            let closed = Some((PortStatus::Closed, Reason::Reset));
+            assert_eq!(scan_type.classify(rst_ack, 0), closed);
+            assert_eq!(scan_type.classify(syn_ack, 0), None);
+        }
+
+        assert_eq!(
+            ScanType::Ack.classify(TcpFlags::RST, 1024),
+            Some((PortStatus::Unfiltered, Reason::Reset))
+        );
+        assert_eq!(
+            ScanType::Window.classify(TcpFlags::RST, 1024),
+            Some((PortStatus::Open, Reason::Reset))
+        );
+        assert_eq!(
+            ScanType::Window.classify(TcpFlags::RST, 0),
+            Some((PortStatus::Closed, Reason::Reset))
+        );
+    }
+
+    /* ECN-capable stacks set ECE (and CWR) on their SYN-ACKs */
+    #[test]
+    fn classify_ecn() {
+        let ecn = TcpFlags::ECE | TcpFlags::CWR;
+
+        assert_eq!(
+            ScanType::Syn.classify(TcpFlags::SYN | TcpFlags::ACK | TcpFlags::ECE, 0),
+            Some((PortStatus::Open, Reason::SynAck))
+        );
+        assert_eq!(
+            ScanType::Syn.classify(TcpFlags::SYN | TcpFlags::ACK | ecn, 0),
+            Some((PortStatus::Open, Reason::SynAck))
+        );
+        assert_eq!(
+            ScanType::Syn.classify(TcpFlags::RST | TcpFlags::ACK | ecn, 0),
+            Some((PortStatus::Closed, Reason::Reset))
+        );
+        assert_eq!(
+            ScanType::Ack.classify(TcpFlags::RST | ecn, 0),
+            Some((PortStatus::Unfiltered, Reason::Reset))
+        );
+    }
+
+    fn status(scan_type: ScanType, code: u8) -> Option<PortStatus> {
+        scan_type.classify_icmp(code).map(|(status, _)| status)
+    }
+
+    #[test]
+    fn classify_icmp() {
+        assert_eq!(status(ScanType::Udp, 3), Some(PortStatus::Closed));
+        assert_eq!(status(ScanType::Udp, 13), Some(PortStatus::Filtered));
+        assert_eq!(status(ScanType::Syn, 3), Some(PortStatus::Filtered));
+        assert_eq!(status(ScanType::Syn, 0), None);
+
+        /* port unreachable means the host speaks the protocol */
+        assert_eq!(status(ScanType::IpProto, 2), Some(PortStatus::Closed));
+        assert_eq!(status(ScanType::IpProto, 3), Some(PortStatus::Open));
+        assert_eq!(status(ScanType::IpProto, 10), Some(PortStatus::Filtered));
+    }
+}
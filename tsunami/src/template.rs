@@ -0,0 +1,203 @@
+use crate::{
+    net::{
+        build_ipv4_packet, build_ipv6_packet, build_tcp_packet, update_checksum, IPV6_HDR_LEN,
+        IP_HDR_LEN, TCP_HDR_LEN,
+    },
+    Port,
+};
+use anyhow::{bail, Result};
+use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpFlags, MutablePacket};
+use std::net::IpAddr;
+
+/// The largest TCP probe, the one over IPv6.
+pub const MAX_TCP_PROBE_LEN: usize = (IPV6_HDR_LEN + TCP_HDR_LEN) as usize;
+
+/* where the fields that differ from probe to probe are, relative to the
+ * start of the IPv4 header and of the TCP header respectively */
+const IPV4_ID: usize = 4;
+const IPV4_CHECKSUM: usize = 10;
+const TCP_SOURCE_PORT: usize = 0;
+const TCP_DEST_PORT: usize = 2;
+const TCP_SEQUENCE: usize = 4;
+const TCP_ACKNOWLEDGEMENT: usize = 8;
+const TCP_CHECKSUM: usize = 16;
+
+/// A TCP probe to a target, built once with the ports, the sequence number
+/// and the IP ID zeroed out. Each probe is a copy of it with those patched
+/// in, and the checksums updated for just the words that changed (RFC 1624)
+/// rather than computed over the whole packet again.
+pub struct TcpTemplate {
+    packet: [u8; MAX_TCP_PROBE_LEN],
+    len: usize,
+    tcp_offset: usize,
+    ipv4: bool,
+    ack: bool,
+}
+
+impl TcpTemplate {
+    pub fn new(src_ip_addr: IpAddr, dest: IpAddr, flags: u8) -> Result<Self> {
+        let mut packet = [0u8; MAX_TCP_PROBE_LEN];
+        let tcp = IpNextHeaderProtocols::Tcp;
+
+        let (len, tcp_offset) = match (src_ip_addr, dest) {
+            (IpAddr::V4(src), IpAddr::V4(dest_v4)) => {
+                let len = (IP_HDR_LEN + TCP_HDR_LEN) as usize;
+                let mut ipv4_packet = build_ipv4_packet(&mut packet[..len], src, dest_v4, tcp);
+                ipv4_packet.set_identification(0);
+                ipv4_packet.set_checksum(pnet::packet::ipv4::checksum(&ipv4_packet.to_immutable()));
+                build_tcp_packet(ipv4_packet.payload_mut(), dest, 0, src_ip_addr, 0, 0, flags)?;
+                (len, IP_HDR_LEN as usize)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dest_v6)) => {
+                let len = MAX_TCP_PROBE_LEN;
+                let mut ipv6_packet = build_ipv6_packet(&mut packet[..len], src, dest_v6, tcp);
+                build_tcp_packet(ipv6_packet.payload_mut(), dest, 0, src_ip_addr, 0, 0, flags)?;
+                (len, IPV6_HDR_LEN as usize)
+            }
+            _ => bail!("address family mismatch between {src_ip_addr} and {dest}"),
+        };
+
+        Ok(Self {
+            packet,
+            len,
+            tcp_offset,
+            ipv4: src_ip_addr.is_ipv4(),
+            ack: flags & TcpFlags::ACK != 0,
+        })
+    }
+
+    /// Fills in the probe to the port, returning the part of `buf` it takes.
+    pub fn fill<'a>(
+        &self,
+        buf: &'a mut [u8; MAX_TCP_PROBE_LEN],
+        src_port: Port,
+        port: Port,
+        sequence: u32,
+        identification: u16,
+    ) -> &'a [u8] {
+        buf.copy_from_slice(&self.packet);
+
+        let tcp = self.tcp_offset;
+        let tcp_checksum = tcp + TCP_CHECKSUM;
+        let (seq_hi, seq_lo) = ((sequence >> 16) as u16, sequence as u16);
+
+        patch(buf, tcp + TCP_SOURCE_PORT, src_port, tcp_checksum);
+        patch(buf, tcp + TCP_DEST_PORT, port, tcp_checksum);
+        patch(buf, tcp + TCP_SEQUENCE, seq_hi, tcp_checksum);
+        patch(buf, tcp + TCP_SEQUENCE + 2, seq_lo, tcp_checksum);
+
+        /* a RST in reply takes its sequence number from this field */
+        if self.ack {
+            patch(buf, tcp + TCP_ACKNOWLEDGEMENT, seq_hi, tcp_checksum);
+            patch(buf, tcp + TCP_ACKNOWLEDGEMENT + 2, seq_lo, tcp_checksum);
+        }
+
+        if self.ipv4 {
+            patch(buf, IPV4_ID, identification, IPV4_CHECKSUM);
+        }
+
+        &buf[..self.len]
+    }
+}
+
+/// Sets the 16-bit word at `offset`, and updates the checksum at `checksum` for it.
+fn patch(buf: &mut [u8], offset: usize, value: u16, checksum: usize) {
+    let word = |buf: &[u8], at: usize| u16::from_be_bytes([buf[at], buf[at + 1]]);
+
+    let old = word(buf, offset);
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+
+    let updated = update_checksum(word(buf, checksum), old, value);
+    buf[checksum..checksum + 2].copy_from_slice(&updated.to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TcpTemplate, MAX_TCP_PROBE_LEN};
+    use pnet::packet::{
+        ip::IpNextHeaderProtocols,
+        ipv4::{self, Ipv4Packet},
+        ipv6::Ipv6Packet,
+        tcp::{self, TcpFlags, TcpPacket},
+        Packet,
+    };
+    use std::net::IpAddr;
+
+    const VALUES: [(u16, u16, u32, u16); 6] = [
+        (0, 0, 0, 0),
+        (0xffff, 0xffff, u32::MAX, 0xffff),
+        (4919, 80, 0x0000_ffff, 1),
+        (1024, 65535, 0xffff_0000, 0x8000),
+        (65535, 1, 0x8000_0001, 0xfffe),
+        (40000, 443, 0xdead_beef, 0x1234),
+    ];
+
+    /* the checksums patched in have to be the ones computed from scratch */
+    fn check(src: IpAddr, dest: IpAddr) {
+        for flags in [
+            TcpFlags::SYN,
+            TcpFlags::ACK,
+            TcpFlags::FIN | TcpFlags::ACK,
+            0,
+        ] {
+            let template = TcpTemplate::new(src, dest, flags).unwrap();
+
+            for (src_port, port, sequence, identification) in VALUES {
+                let mut buf = [0u8; MAX_TCP_PROBE_LEN];
+                let packet = template.fill(&mut buf, src_port, port, sequence, identification);
+
+                let (segment, checksum) = match (src, dest) {
+                    (IpAddr::V4(src), IpAddr::V4(dest)) => {
+                        let ipv4_packet = Ipv4Packet::new(packet).unwrap();
+                        assert_eq!(ipv4_packet.get_identification(), identification);
+                        assert_eq!(ipv4_packet.get_checksum(), ipv4::checksum(&ipv4_packet));
+
+                        let segment = TcpPacket::new(ipv4_packet.payload()).unwrap();
+                        let checksum = tcp::ipv4_checksum(&segment, &src, &dest);
+                        (segment.packet().to_vec(), checksum)
+                    }
+                    (IpAddr::V6(src), IpAddr::V6(dest)) => {
+                        let ipv6_packet = Ipv6Packet::new(packet).unwrap();
+                        assert_eq!(ipv6_packet.get_next_header(), IpNextHeaderProtocols::Tcp);
+
+                        let segment = TcpPacket::new(ipv6_packet.payload()).unwrap();
+                        let checksum = tcp::ipv6_checksum(&segment, &src, &dest);
+                        (segment.packet().to_vec(), checksum)
+                    }
+                    _ => unreachable!(),
+                };
+
+                let segment = TcpPacket::new(&segment).unwrap();
+                assert_eq!(segment.get_source(), src_port);
+                assert_eq!(segment.get_destination(), port);
+                assert_eq!(segment.get_sequence(), sequence);
+                assert_eq!(segment.get_flags(), flags);
+                if flags & TcpFlags::ACK != 0 {
+                    assert_eq!(segment.get_acknowledgement(), sequence);
+                }
+                assert_eq!(segment.get_checksum(), checksum, "{flags:#x} {sequence:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn fill_ipv4() {
+        check(
+            "192.0.2.2".parse().unwrap(),
+            "198.51.100.7".parse().unwrap(),
+        );
+        check(
+            "0.0.0.0".parse().unwrap(),
+            "255.255.255.255".parse().unwrap(),
+        );
+    }
+
+    #[test]
+    fn fill_ipv6() {
+        check("fd00::2".parse().unwrap(), "2001:db8::1".parse().unwrap());
+        check(
+            "::".parse().unwrap(),
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse().unwrap(),
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    net::{
+        build_ipv4_packet, build_ipv6_packet, build_sctp_packet, build_udp_packet,
+        create_send_sock, send_to, sequence_number, SourcePorts, IPV6_HDR_LEN, IP_HDR_LEN,
+        SCTP_HDR_LEN, SCTP_INIT_LEN, SOURCE_PORT, UDP_HDR_LEN,
+    },
+    pacing::Pacer,
+    payloads::udp_payload,
+    permutation::Permutation,
+    scan::ScanType,
+    template::{TcpTemplate, MAX_TCP_PROBE_LEN},
+    Port, Probe, Stamp,
+};
+use anyhow::{bail, Result};
+use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::checksum, Packet};
+use raw_socket::{
+    tokio::prelude::{Level, Name, RawSocket},
+    Domain,
+};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    net::IpAddr,
+    time::Instant,
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, instrument};
+
+/// Sends the probes of a raw scan. There's a single long-lived socket per
+/// address family, and nothing is remembered about the probes once they're
+/// out: the receiver tells the replies apart by their sequence number cookies.
+/// The TCP probes are patched into a template built once per target, while
+/// the others, whose payloads differ from port to port, are built as they go.
+pub struct Transmitter {
+    sock: Option<RawSocket>,
+    sock6: Option<RawSocket>,
+    templates: HashMap<IpAddr, TcpTemplate>,
+    src_ip_addrs: HashMap<IpAddr, IpAddr>,
+    key: RandomState,
+    scan_type: ScanType,
+    seed: u64,
+    source_ports: SourcePorts,
+}
+
+impl Transmitter {
+    pub fn new(
+        src_ip_addrs: HashMap<IpAddr, IpAddr>,
+        key: RandomState,
+        scan_type: ScanType,
+        seed: u64,
+        source_ports: SourcePorts,
+        interface: Option<&str>,
+    ) -> Result<Self> {
+        /* only open the sockets for the address families we're going to see */
+        let sock = match src_ip_addrs.keys().any(IpAddr::is_ipv4) {
+            true => {
+                let sock = create_send_sock(Domain::ipv4(), interface)?;
+                sock.set_sockopt(Level::IPV4, Name::IPV4_HDRINCL, &1i32)?;
+                Some(sock)
+            }
+            false => None,
+        };
+        let sock6 = match src_ip_addrs.keys().any(IpAddr::is_ipv6) {
+            true => Some(create_send_sock(Domain::ipv6(), interface)?),
+            false => None,
+        };
+
+        let templates = match scan_type.ip_protocol(0) {
+            IpNextHeaderProtocols::Tcp if scan_type != ScanType::IpProto => src_ip_addrs
+                .iter()
+                .map(|(dest, src_ip_addr)| {
+                    TcpTemplate::new(*src_ip_addr, *dest, scan_type.flags())
+                        .map(|template| (*dest, template))
+                })
+                .collect::<Result<_>>()?,
+            _ => HashMap::new(),
+        };
+
+        Ok(Self {
+            sock,
+            sock6,
+            templates,
+            src_ip_addrs,
+            key,
+            scan_type,
+            seed,
+            source_ports,
+        })
+    }
+
+    /// Sends a probe to each of the (host, port) pairs, in a pseudo-random
+    /// order, so that no host gets its ports hammered in a row. The receiver
+    /// is told when each one went out, to time its reply.
+    #[instrument(skip_all, name = "transmitter", fields(probes = probes.len()))]
+    pub async fn transmit(
+        &self,
+        probes: &[Probe],
+        pacer: &mut Pacer,
+        sent: &UnboundedSender<Stamp>,
+    ) -> Result<()> {
+        let permutation = Permutation::new(probes.len() as u64, self.seed);
+
+        for index in permutation.iter() {
+            let (dest, port) = probes[index as usize];
+            /* the receiver only goes away early if it failed, which awaiting it
+             * tells; it hears of the probe before it's out, since the reply
+             * to it could otherwise get there first (over loopback, say) */
+            if sent.send(((dest, port), Instant::now())).is_err() {
+                debug!("the receiver is gone");
+                break;
+            }
+
+            let len = self.send(dest, port).await?;
+            pacer.tick(len).await;
+        }
+
+        debug!("sent the probes");
+
+        Ok(())
+    }
+
+    /// Crafts the probe to the port and sends it off, returning its size.
+    async fn send(&self, dest: IpAddr, port: Port) -> Result<usize> {
+        let src_ip_addr = self.src_ip_addrs[&dest];
+        let key = &self.key;
+        let scan_type = self.scan_type;
+        let src_port = self.source_ports.pick(key, src_ip_addr, dest, port);
+
+        if let Some(template) = self.templates.get(&dest) {
+            let mut buf = [0u8; MAX_TCP_PROBE_LEN];
+            let sequence = sequence_number(key, src_ip_addr, src_port, dest, port);
+            let packet = template.fill(&mut buf, src_port, port, sequence, rand::random());
+
+            /* as below, the port of a raw IPv6 socket address stays zeroed */
+            match (dest, &self.sock, &self.sock6) {
+                (IpAddr::V4(_), Some(sock), _) => send_to(sock, packet, dest, port).await?,
+                (IpAddr::V6(_), _, Some(sock6)) => send_to(sock6, packet, dest, 0).await?,
+                _ => bail!("no socket to send the probe to {dest} over"),
+            }
+            return Ok(packet.len());
+        }
+
+        let protocol = scan_type.ip_protocol(port);
+        let transport_buf = match protocol {
+            /* the IP header alone is enough to tell whether the protocol is supported */
+            _ if scan_type == ScanType::IpProto => Vec::new(),
+            IpNextHeaderProtocols::Udp => {
+                let payload = udp_payload(port);
+                let mut udp_buf = vec![0u8; UDP_HDR_LEN as usize + payload.len()];
+                build_udp_packet(&mut udp_buf, dest, port, src_ip_addr, src_port, payload)?;
+                udp_buf
+            }
+            IpNextHeaderProtocols::Sctp => {
+                let mut sctp_buf = vec![0u8; (SCTP_HDR_LEN + SCTP_INIT_LEN) as usize];
+                let initiate_tag = sequence_number(key, src_ip_addr, src_port, dest, port);
+                build_sctp_packet(&mut sctp_buf, src_port, port, initiate_tag);
+                sctp_buf
+            }
+            _ => bail!("the tcp probe to {dest} has no template"),
+        };
+
+        match (src_ip_addr, dest, &self.sock, &self.sock6) {
+            (IpAddr::V4(src), IpAddr::V4(dest), Some(sock), _) => {
+                let mut ipv4_buf = vec![0u8; IP_HDR_LEN as usize + transport_buf.len()];
+                let mut ipv4_packet = build_ipv4_packet(&mut ipv4_buf, src, dest, protocol);
+                ipv4_packet.set_payload(&transport_buf);
+
+                /* without a transport header, the cookie goes in the identification
+                 * field instead, which ICMP errors quote along with the protocol */
+                if scan_type == ScanType::IpProto {
+                    let dest = IpAddr::V4(dest);
+                    let cookie = sequence_number(key, src_ip_addr, SOURCE_PORT, dest, port);
+                    ipv4_packet.set_identification(cookie as u16);
+                    ipv4_packet.set_checksum(checksum(&ipv4_packet.to_immutable()));
+                }
+
+                send_to(sock, ipv4_packet.packet(), IpAddr::V4(dest), port).await?;
+                Ok(ipv4_buf.len())
+            }
+            (IpAddr::V6(src), IpAddr::V6(dest), _, Some(sock6)) => {
+                let mut ipv6_buf = vec![0u8; IPV6_HDR_LEN as usize + transport_buf.len()];
+                let mut ipv6_packet = build_ipv6_packet(&mut ipv6_buf, src, dest, protocol);
+                ipv6_packet.set_payload(&transport_buf);
+
+                /* the port of a raw IPv6 socket address is the protocol number,
+                 * so leave it zeroed and let IPPROTO_RAW take over */
+                send_to(sock6, ipv6_packet.packet(), IpAddr::V6(dest), 0).await?;
+                Ok(ipv6_buf.len())
+            }
+            _ => bail!("address family mismatch between {src_ip_addr} and {dest}"),
+        }
+    }
+}
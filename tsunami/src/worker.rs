@@ -1,41 +1,59 @@
-use crate::net::{build_ipv4_packet, build_tcp_packet, create_send_sock, IP_HDR_LEN, TCP_HDR_LEN};
-use crate::Port;
+use crate::{Port, PortStatus, Reason, Verdict};
 use anyhow::Result;
-use pnet::packet::Packet;
-use raw_socket::tokio::prelude::{Level, Name};
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{io::ErrorKind, net::IpAddr, sync::Arc};
 use tokio::{
-    sync::Semaphore,
-    time::{sleep, Duration},
+    net::TcpStream,
+    sync::{mpsc::Sender, Semaphore},
+    time::{sleep, timeout, Duration},
 };
 use tracing::{debug, info, instrument};
 
-#[instrument(skip(semaphore, nap_after_spawn), name = "worker")]
-pub async fn inspect(
-    dest: Ipv4Addr,
+/// Inspects the port with an ordinary connect(), which doesn't need any
+/// privileges. Since there's no raw socket for the receiver to listen on,
+/// the outcome is sent to it directly. Timeouts are left for the receiver
+/// to retry, just like unanswered probes.
+#[instrument(
+    skip(semaphore, verdicts, connect_timeout, nap_after_spawn),
+    name = "worker"
+)]
+pub async fn connect(
+    dest: IpAddr,
     port: Port,
     semaphore: Arc<Semaphore>,
-    src_ip_addr: Ipv4Addr,
+    verdicts: Sender<Verdict>,
+    connect_timeout: u64,
     nap_after_spawn: f64,
 ) -> Result<()> {
     debug!("trying to acquire the permit");
     if let Ok(_permit) = semaphore.acquire().await {
         debug!("acquired the permit");
-        let sock = create_send_sock()?;
 
-        let mut ipv4_buf = vec![0u8; (IP_HDR_LEN + TCP_HDR_LEN) as usize];
-        let mut ipv4_packet = build_ipv4_packet(&mut ipv4_buf, dest);
-
-        let mut tcp_buf = vec![0u8; TCP_HDR_LEN as usize];
-        let tcp_packet = build_tcp_packet(&mut tcp_buf, dest, port, src_ip_addr)?;
-
-        ipv4_packet.set_payload(tcp_packet.packet());
-
-        sock.set_sockopt(Level::IPV4, Name::IPV4_HDRINCL, &1i32)?;
-        sock.send_to(ipv4_packet.packet(), (dest, port)).await?;
-        debug!("sent the probe");
-
-        /* Sleep a little after the sent probe, for good measure. */
+        let attempt = TcpStream::connect((dest, port));
+        let verdict = match timeout(Duration::from_millis(connect_timeout), attempt).await {
+            Ok(Ok(_stream)) => Some((PortStatus::Open, Reason::SynAck)),
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => {
+                Some((PortStatus::Closed, Reason::ConnRefused))
+            }
+            Ok(Err(e)) => {
+                debug!("connect failed: {e}");
+                None
+            }
+            Err(_) => None,
+        };
+        debug!("tried to connect");
+
+        if let Some((port_status, reason)) = verdict {
+            /* the receiver may have given up on the port in the meantime */
+            if verdicts
+                .send(((dest, port), port_status, reason))
+                .await
+                .is_err()
+            {
+                debug!("the receiver is gone");
+            }
+        }
+
+        /* Sleep a little after the attempt, for good measure. */
         sleep(Duration::from_secs_f64(nap_after_spawn / 1000.0)).await;
     }
 